@@ -0,0 +1,166 @@
+//! Bech32 (BIP 173) encoding of keys: a human readable prefix that tells which kind of key it is,
+//! and a checksum that catches typos, so a mistyped key is rejected instead of silently being a different key.
+
+use crate::Error;
+use std::iter;
+use zeroize::Zeroizing;
+
+pub const PUBLIC_KEY_HRP: &str = "lorenz";
+pub const SECRET_KEY_HRP: &str = "lorenz-secret";
+pub const VERIFYING_KEY_HRP: &str = "lorenz-verify";
+pub const SIGNING_KEY_HRP: &str = "lorenz-sign";
+pub const HYBRID_PUBLIC_KEY_HRP: &str = "lorenz-pq";
+pub const HYBRID_SECRET_KEY_HRP: &str = "lorenz-pq-secret";
+
+const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const GENERATOR: [u32; 5] = [0x3b6a_57b2, 0x2650_8e6d, 0x1ea1_19fa, 0x3d42_33dd, 0x2a14_62b3];
+const CHECKSUM_LEN: usize = 6;
+const MAX_LEN: usize = 90;
+
+/// What a human readable prefix stands for, for error messages.
+fn kind_name(hrp: &str) -> &'static str {
+    match hrp {
+        PUBLIC_KEY_HRP => "public key",
+        SECRET_KEY_HRP => "private key",
+        VERIFYING_KEY_HRP => "verifying key",
+        SIGNING_KEY_HRP => "signing key",
+        HYBRID_PUBLIC_KEY_HRP => "hybrid public key",
+        HYBRID_SECRET_KEY_HRP => "hybrid private key",
+        _ => "unknown kind of key",
+    }
+}
+
+fn polymod<I: IntoIterator<Item = u8>>(values: I) -> u32 {
+    let mut chk = 1u32;
+    for value in values {
+        let top = chk >> 25;
+        chk = (chk & 0x01ff_ffff) << 5 ^ u32::from(value);
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= generator;
+            }
+        }
+    }
+    chk
+}
+
+fn hrp_expand(hrp: &str) -> impl Iterator<Item = u8> + '_ {
+    hrp.bytes().map(|b| b >> 5).chain(iter::once(0)).chain(hrp.bytes().map(|b| b & 31))
+}
+
+/// Regroups `data` from `from` bits per value to `to` bits per value.
+/// Without `pad` the leftover bits must be zero padding shorter than `from`, as an encoder would produce.
+fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Option<Zeroizing<Vec<u8>>> {
+    let mut acc = 0u32;
+    let mut bits = 0u32;
+    let max = (1u32 << to) - 1;
+    let mut res = Zeroizing::new(Vec::with_capacity(data.len() * from as usize / to as usize + 1));
+    for &value in data {
+        acc = (acc << from | u32::from(value)) & 0xffff;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            res.push((acc >> bits & max) as u8);
+        }
+    }
+    if pad {
+        if bits > 0 {
+            res.push((acc << (to - bits) & max) as u8);
+        }
+    } else if bits >= from || acc << (to - bits) & max != 0 {
+        return None;
+    }
+    Some(res)
+}
+
+pub fn encode(hrp: &str, data: &[u8]) -> Zeroizing<String> {
+    let values = convert_bits(data, 8, 5, true).expect("padding always succeeds");
+    let checksum = polymod(hrp_expand(hrp).chain(values.iter().cloned()).chain(iter::repeat_n(0, CHECKSUM_LEN))) ^ 1;
+    let mut res = Zeroizing::new(String::with_capacity(hrp.len() + 1 + values.len() + CHECKSUM_LEN));
+    res.push_str(hrp);
+    res.push('1');
+    res.extend(values.iter().map(|&v| char::from(CHARSET[usize::from(v)])));
+    res.extend((0..CHECKSUM_LEN).map(|i| char::from(CHARSET[(checksum >> (5 * (5 - i)) & 31) as usize])));
+    res
+}
+
+/// Returns the human readable prefix and the decoded data.
+/// Strings longer than `max_len` are rejected, BIP 173 limits them to `MAX_LEN`.
+pub fn decode(s: &str, max_len: usize) -> Result<(String, Zeroizing<Vec<u8>>), Error> {
+    if s.len() > max_len || (s.bytes().any(|b| b.is_ascii_lowercase()) && s.bytes().any(|b| b.is_ascii_uppercase())) {
+        return Err(Error::BadKey);
+    }
+    let s = Zeroizing::new(s.to_ascii_lowercase());
+    let separator = s.rfind('1').ok_or(Error::BadKey)?;
+    let (hrp, data) = (&s[..separator], &s[separator + 1..]);
+    if hrp.is_empty() || data.len() < CHECKSUM_LEN || !hrp.bytes().all(|b| (33..=126).contains(&b)) {
+        return Err(Error::BadKey);
+    }
+    let mut values = Zeroizing::new(Vec::with_capacity(data.len()));
+    for b in data.bytes() {
+        values.push(CHARSET.iter().position(|&c| c == b).ok_or(Error::BadKey)? as u8);
+    }
+    if polymod(hrp_expand(hrp).chain(values.iter().cloned())) != 1 {
+        return Err(Error::BadChecksum);
+    }
+    let data = convert_bits(&values[..values.len() - CHECKSUM_LEN], 5, 8, false).ok_or(Error::BadKey)?;
+    Ok((hrp.to_owned(), data))
+}
+
+/// Decodes a 32 byte key, and checks that it's the kind of key `hrp` stands for.
+pub fn decode_key(s: &str, hrp: &'static str) -> Result<Zeroizing<[u8; 32]>, Error> {
+    let data = decode_key_bytes(s, hrp, 32)?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    key.copy_from_slice(&data);
+    Ok(key)
+}
+
+/// Decodes a key of `len` bytes, and checks that it's the kind of key `hrp` stands for.
+/// Keys can be longer than `MAX_LEN` characters, past it the checksum no longer catches every combination of 4 typos,
+/// but still misses a random typo with a probability of about one in a billion.
+pub fn decode_key_bytes(s: &str, hrp: &'static str, len: usize) -> Result<Zeroizing<Vec<u8>>, Error> {
+    let max_len = MAX_LEN.max(hrp.len() + 1 + (len * 8).div_ceil(5) + CHECKSUM_LEN);
+    let (found, data) = decode(s, max_len)?;
+    if found != hrp {
+        return Err(Error::WrongKeyKind(kind_name(hrp), kind_name(&found)));
+    }
+    if data.len() != len {
+        return Err(Error::BadKeyLength);
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bech32_encoding() {
+        // Test vectors from BIP 173.
+        for valid in &[
+            "A12UEL5L",
+            "an83characterlonghumanreadablepartthatcontainsthenumber1andtheexcludedcharactersbio1tt5tgs",
+            "abcdef1qpzry9x8gf2tvdw0s3jn54khce6mua7lmqqqxw",
+            "split1checkupstagehandshakeupstreamerranterredcaperred2y9e3w",
+        ] {
+            let (hrp, data) = decode(valid, MAX_LEN).unwrap();
+            assert_eq!(&*encode(&hrp, &data), &valid.to_ascii_lowercase());
+        }
+        assert!(matches!(decode("A12UEL5A", MAX_LEN), Err(Error::BadChecksum)));
+        assert!(matches!(decode("A12uEL5L", MAX_LEN), Err(Error::BadKey)));
+        assert!(matches!(decode("pzry9x0s0muk", MAX_LEN), Err(Error::BadKey)));
+        assert!(matches!(decode("1pzry9x0s0muk", MAX_LEN), Err(Error::BadKey)));
+        assert!(matches!(decode("x1b4n0q5v", MAX_LEN), Err(Error::BadKey)));
+
+        let key = [7u8; 32];
+        let encoded = encode(PUBLIC_KEY_HRP, &key);
+        assert!(encoded.starts_with("lorenz1"));
+        assert_eq!(*decode_key(&encoded, PUBLIC_KEY_HRP).unwrap(), key);
+        assert_eq!(*decode_key(&encoded.to_ascii_uppercase(), PUBLIC_KEY_HRP).unwrap(), key);
+        assert!(matches!(decode_key(&encoded, SECRET_KEY_HRP), Err(Error::WrongKeyKind("private key", "public key"))));
+        let mut typo = encoded.to_string();
+        typo.replace_range(10..11, if &typo[10..11] == "q" { "p" } else { "q" });
+        assert!(matches!(decode_key(&typo, PUBLIC_KEY_HRP), Err(Error::BadChecksum)));
+        assert!(matches!(decode_key(&encode(PUBLIC_KEY_HRP, &key[1..]), PUBLIC_KEY_HRP), Err(Error::BadKeyLength)));
+    }
+}
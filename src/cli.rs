@@ -1,6 +1,8 @@
-use crate::x25519::UserSecretKey;
-use rustc_hex::{FromHex, FromHexError};
+use lorenz::{
+    Error, HybridPublicKey, HybridSecretKey, Scheme, StanzaRef, UserPublicKey, UserSecretKey, UserSigningKey, UserVerifyingKey,
+};
 use std::path::PathBuf;
+use std::str::FromStr;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -11,37 +13,444 @@ pub enum Options {
     GenerateKeys {
         #[structopt(default_value = "1")]
         amount: u8,
+        /// Write the private key into this file encrypted under a passphrase, instead of printing it.
+        #[structopt(long, parse(from_os_str))]
+        out: Option<PathBuf>,
+        /// Generate Ed25519 keys for signing files with `encrypt --sign-with`, instead of encryption keys.
+        #[structopt(long, raw(conflicts_with = r#""out""#))]
+        signing: bool,
+        /// Derive each key from a list of 24 words, which can be written down and turned back into the key with `key recover`.
+        #[structopt(long, raw(conflicts_with = r#""signing""#))]
+        mnemonic: bool,
+        /// Generate hybrid X25519 + ML-KEM-768 keys, files encrypted to them stay confidential even if X25519 gets broken.
+        #[structopt(long, raw(conflicts_with_all = r#"&["out", "signing", "mnemonic"]"#))]
+        hybrid: bool,
     },
     /// Encrypt a file
     #[structopt(name = "encrypt")]
     Encrypt {
-        #[structopt(parse(try_from_str = "parse_hex32"), raw(required = "true"))]
-        public_keys: Vec<[u8; 32]>,
+        /// Encrypt to a public key, regular or hybrid, to a contact or identity from the keyring by name or fingerprint
+        /// (at least its first 16 digits), or to every member of a group with `@group`. Can be repeated.
+        /// SSH ed25519 public keys are accepted too, quoted as a single argument: "ssh-ed25519 AAAA...".
+        #[structopt(
+            short = "r",
+            long = "recipient",
+            raw(number_of_values = "1", required_unless_one = r#"&["passphrase", "share_holders"]"#)
+        )]
+        recipients: Vec<String>,
+        /// Split the file key between share holders, so `--threshold` of them together can decrypt the file with `combine`.
+        /// Takes the same names, fingerprints, groups and public keys as `-r`, can be repeated.
+        #[structopt(long = "share-holder", raw(number_of_values = "1", requires = r#""threshold""#))]
+        share_holders: Vec<String>,
+        /// How many of the share holders are needed to decrypt the file.
+        #[structopt(long, raw(requires = r#""share_holders""#))]
+        threshold: Option<u8>,
+        /// Prompt for a passphrase that can also decrypt the file, with or without public keys.
+        #[structopt(long)]
+        passphrase: bool,
+        /// Tag every public key recipient so decrypting doesn't have to try every wrapped key.
+        /// The tags don't reveal the recipients, but they're left out by default.
+        #[structopt(long)]
+        hints: bool,
+        /// Sign the file with this signing key, only the recipients can see who signed it.
+        #[structopt(long = "sign-with", parse(try_from_str))]
+        sign_with: Option<UserSigningKey>,
+        /// Use this private key in the key agreement, so the recipient can check that the file came from you with `decrypt --sender`.
+        /// Only works with a single recipient, any other one could forge the file.
+        #[structopt(long, parse(try_from_str))]
+        sender: Option<UserSecretKey>,
+        /// The file to encrypt, `-` reads from stdin.
         #[structopt(parse(from_os_str))]
         file: PathBuf,
-        #[structopt(long, default_value = "AES")]
-        mode: String,
+        #[structopt(long, default_value = "AES", parse(try_from_str))]
+        mode: Scheme,
+        /// Where to write the encrypted file, `-` writes to stdout.
+        /// Defaults to `<file>.lorenz`, or stdout when reading from stdin.
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
     },
     /// Decrypt a file
     #[structopt(name = "decrypt")]
     Decrypt {
-        #[structopt(parse(try_from_str))]
-        private_key: UserSecretKey,
+        #[structopt(flatten)]
+        key: KeySource,
+        /// Fail unless the file is signed by this verifying key.
+        /// The signature is checked at the end, output written to stdout before that is unverified.
+        #[structopt(long = "expect-signer", parse(try_from_str))]
+        expect_signer: Option<UserVerifyingKey>,
+        /// Fail unless the file was encrypted with `encrypt --sender` by the owner of this public key.
+        #[structopt(long, parse(try_from_str))]
+        sender: Option<UserPublicKey>,
+        /// The file to decrypt, `-` reads from stdin.
         #[structopt(parse(from_os_str))]
         file: PathBuf,
-        #[structopt(long, default_value = "AES")]
-        mode: String,
+        /// A share file written by `unwrap-share` for your key, repeat it for each share. The shares are combined to decrypt
+        /// a file encrypted with `--threshold`.
+        #[structopt(long, parse(from_os_str), raw(number_of_values = "1"))]
+        shares: Vec<PathBuf>,
+        /// Where to write the decrypted file, `-` writes to stdout.
+        /// Defaults to `<file>` without the `.lorenz` extension, or stdout when reading from stdin.
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
     },
+    /// Check that a file is intact and can be decrypted, without writing the decrypted data anywhere
+    #[structopt(name = "verify")]
+    Verify {
+        #[structopt(flatten)]
+        key: KeySource,
+        /// Fail unless the file is signed by this verifying key.
+        #[structopt(long = "expect-signer", parse(try_from_str))]
+        expect_signer: Option<UserVerifyingKey>,
+        /// Fail unless the file was encrypted with `encrypt --sender` by the owner of this public key.
+        #[structopt(long, parse(try_from_str))]
+        sender: Option<UserPublicKey>,
+        /// The file to verify, `-` reads from stdin.
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Decrypt a file encrypted with `--threshold`, prompting the share holders for their private keys one after the other
+    /// until enough shares of the file key are collected
+    #[structopt(name = "combine")]
+    Combine {
+        /// The file to decrypt, it's read again for every share holder so it can't be stdin.
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        /// Where to write the decrypted file, `-` writes to stdout.
+        /// Defaults to `<file>` without the `.lorenz` extension.
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Unwrap your share of a file encrypted with `--threshold` into a share file for the person combining the shares,
+    /// who can then decrypt the file with `decrypt --shares` without ever seeing your private key
+    #[structopt(name = "unwrap-share")]
+    UnwrapShare {
+        #[structopt(flatten)]
+        key: KeySource,
+        /// The encrypted file, `-` reads from stdin.
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        /// Who combines the shares, the share file is encrypted to them.
+        /// A name, fingerprint or public key, like `encrypt -r`.
+        #[structopt(long = "to")]
+        combiner: String,
+        /// Where to write the share file, `-` writes to stdout.
+        /// Defaults to `<file>.share`, or stdout when reading from stdin.
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Give more public keys access to an encrypted file, without re-encrypting its content
+    #[structopt(name = "add-recipient")]
+    AddRecipient {
+        #[structopt(flatten)]
+        key: KeySource,
+        /// Give access to a public key, regular or hybrid, a name or fingerprint from the keyring, or a `@group`,
+        /// like `encrypt -r`. Can be repeated.
+        #[structopt(short = "r", long = "recipient", raw(number_of_values = "1"), required = true)]
+        recipients: Vec<String>,
+        /// The encrypted file, `-` reads from stdin.
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        /// Where to write the updated file, `-` writes to stdout.
+        /// Defaults to replacing `<file>`, or stdout when reading from stdin.
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Remove wrapped keys from an encrypted file, without re-encrypting its content.
+    /// Anyone who kept a copy of the old file can still decrypt it
+    #[structopt(name = "remove-recipient")]
+    RemoveRecipient {
+        #[structopt(flatten)]
+        key: KeySource,
+        /// A stanza to remove, by index or by `0x` hint as listed by `lorenz inspect`. Can be repeated.
+        #[structopt(short = "s", long = "stanza", parse(try_from_str), raw(number_of_values = "1"), required = true)]
+        stanzas: Vec<StanzaRef>,
+        /// The encrypted file, `-` reads from stdin.
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        /// Where to write the updated file, `-` writes to stdout.
+        /// Defaults to replacing `<file>`, or stdout when reading from stdin.
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Show what can be learned about an encrypted file without a key
+    #[structopt(name = "inspect")]
+    Inspect {
+        /// Print the details as JSON.
+        #[structopt(long)]
+        json: bool,
+        /// The encrypted file, `-` reads from stdin.
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Manage the keyring of named contacts and identities
+    #[structopt(name = "key")]
+    Key {
+        #[structopt(subcommand)]
+        command: KeyCommand,
+    },
+    /// Manage named groups of recipients, used as `encrypt -r @group`
+    #[structopt(name = "group")]
+    Group {
+        #[structopt(subcommand)]
+        command: GroupCommand,
+    },
+}
+
+/// The private key a file is decrypted with. Without any of them every identity in the keyring is tried.
+#[derive(Debug, StructOpt)]
+pub struct KeySource {
+    /// A private key, regular or hybrid, or the name of an identity in the keyring.
+    #[structopt(short = "k", long = "key")]
+    pub key: Option<String>,
+    /// Read the private key from a passphrase protected key file written by `generate-keys --out`.
+    #[structopt(long = "key-file", parse(from_os_str), raw(conflicts_with = r#""key""#))]
+    pub key_file: Option<PathBuf>,
+    /// Use an OpenSSH ed25519 private key, like `~/.ssh/id_ed25519`, prompting for its passphrase if it's encrypted.
+    #[structopt(long = "ssh-key", parse(from_os_str), raw(conflicts_with_all = r#"&["key", "key_file"]"#))]
+    pub ssh_key: Option<PathBuf>,
+    /// Prompt for the passphrase the file was encrypted with.
+    #[structopt(long, raw(conflicts_with_all = r#"&["key", "key_file", "ssh_key"]"#))]
+    pub passphrase: bool,
+}
+
+/// A public key on the command line, a hybrid one is told apart by its prefix.
+#[derive(Debug)]
+pub enum PublicKeyArg {
+    X25519(UserPublicKey),
+    Hybrid(HybridPublicKey),
+}
+
+/// A private key on the command line, a hybrid one is told apart by its prefix.
+#[derive(Debug)]
+pub enum SecretKeyArg {
+    X25519(UserSecretKey),
+    Hybrid(HybridSecretKey),
+}
+
+impl FromStr for PublicKeyArg {
+    type Err = Error;
+
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        if key.to_ascii_lowercase().starts_with("lorenz-pq1") {
+            key.parse().map(PublicKeyArg::Hybrid)
+        } else {
+            key.parse().map(PublicKeyArg::X25519)
+        }
+    }
 }
 
-pub fn parse_hex32(hex: &str) -> Result<[u8; 32], FromHexError> {
-    let hex = if "0x" == hex.chars().take(2).collect::<String>().as_str() { hex.chars().skip(2).collect() } else { hex.to_owned() };
-    if hex.len() != 64 {
-        // TODO: formalize an error
-        return Err(FromHexError::InvalidHexLength);
+impl FromStr for SecretKeyArg {
+    type Err = Error;
+
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        if key.to_ascii_lowercase().starts_with("lorenz-pq-secret1") {
+            key.parse().map(SecretKeyArg::Hybrid)
+        } else {
+            key.parse().map(SecretKeyArg::X25519)
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub enum KeyCommand {
+    /// Add a contact's public key to the keyring, or one of your private keys with `--identity`
+    #[structopt(name = "import")]
+    Import {
+        /// The key is a private key, files encrypted to it are decrypted without passing it explicitly.
+        #[structopt(long)]
+        identity: bool,
+        name: String,
+        key: String,
+    },
+    /// Generate a new identity in the keyring and print its public key
+    #[structopt(name = "generate")]
+    Generate { name: String },
+    /// Rebuild a private key from the words printed by `generate-keys --mnemonic`, prompting for them.
+    /// With a name the key is added to the keyring as an identity, otherwise it's printed
+    #[structopt(name = "recover")]
+    Recover { name: Option<String> },
+    /// Derive a subkey for a label like `project/payments/2026` from a master identity, or from its mnemonic.
+    /// The same master and label always give the same key, so subkeys don't need their own backups
+    #[structopt(name = "derive")]
+    Derive {
+        /// The identity in the keyring to derive from.
+        #[structopt(long, raw(required_unless = r#""mnemonic""#))]
+        from: Option<String>,
+        /// Prompt for the words of a `generate-keys --mnemonic` key to derive from, instead of an identity.
+        #[structopt(long, raw(conflicts_with = r#""from""#))]
+        mnemonic: bool,
+        /// Add the subkey to the keyring as an identity with this name, instead of printing it.
+        #[structopt(long)]
+        name: Option<String>,
+        label: String,
+    },
+    /// List the contacts and identities in the keyring
+    #[structopt(name = "list")]
+    List,
+    /// Print the public key of a contact or identity
+    #[structopt(name = "export")]
+    Export { name: String },
+    /// Remove a contact or identity from the keyring
+    #[structopt(name = "remove")]
+    Remove { name: String },
+    /// Convert a key in the older `0x` hex form to its current encoding, hex doesn't say what kind of key it is
+    #[structopt(name = "from-hex")]
+    FromHex {
+        /// The kind of key: `public`, `private`, `signing` or `verifying`.
+        #[structopt(parse(try_from_str))]
+        kind: HexKeyKind,
+        key: String,
+    },
+}
+
+/// What a hex key is, the hex itself can't tell.
+#[derive(Debug, Clone, Copy)]
+pub enum HexKeyKind {
+    Public,
+    Private,
+    Signing,
+    Verifying,
+}
+
+impl FromStr for HexKeyKind {
+    type Err = String;
+
+    fn from_str(kind: &str) -> Result<Self, Self::Err> {
+        match kind {
+            "public" => Ok(HexKeyKind::Public),
+            "private" => Ok(HexKeyKind::Private),
+            "signing" => Ok(HexKeyKind::Signing),
+            "verifying" => Ok(HexKeyKind::Verifying),
+            _ => Err(format!("Unknown kind of key {}, expected public, private, signing or verifying", kind)),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub enum GroupCommand {
+    /// Create or replace a group, members are key names or fingerprints from the keyring, or public keys
+    #[structopt(name = "set")]
+    Set {
+        name: String,
+        #[structopt(required = true)]
+        members: Vec<String>,
+    },
+    /// List the groups and their members
+    #[structopt(name = "list")]
+    List,
+    /// Remove a group, its members stay in the keyring
+    #[structopt(name = "remove")]
+    Remove { name: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Options {
+        Options::from_iter_safe(args).unwrap()
+    }
+
+    #[test]
+    fn options_between_positionals() {
+        let secret = UserSecretKey::new().unwrap();
+        // The display of a private key is a block, its first line holds the key itself.
+        let key = secret.to_string().lines().next().unwrap().trim_start_matches("privateKey: ").to_string();
+        let public = secret.get_public().to_string();
+
+        match parse(&["lorenz", "decrypt", "-k", &key, "-o", "-", "f.lorenz"]) {
+            Options::Decrypt { key: KeySource { key: Some(k), .. }, file, output, .. } => {
+                assert_eq!(k, key);
+                assert_eq!(file, PathBuf::from("f.lorenz"));
+                assert_eq!(output, Some(PathBuf::from("-")));
+            }
+            options => panic!("{:?}", options),
+        }
+        match parse(&["lorenz", "decrypt", "--sender", &public, "-k", &key, "f.lorenz"]) {
+            Options::Decrypt { key: KeySource { key: Some(k), .. }, sender: Some(sender), file, .. } => {
+                assert_eq!(k, key);
+                assert_eq!(sender.to_string(), public);
+                assert_eq!(file, PathBuf::from("f.lorenz"));
+            }
+            options => panic!("{:?}", options),
+        }
+        match parse(&["lorenz", "verify", "-k", &key, "--sender", &public, "f.lorenz"]) {
+            Options::Verify { key: KeySource { key: Some(k), .. }, sender: Some(_), file, .. } => {
+                assert_eq!(k, key);
+                assert_eq!(file, PathBuf::from("f.lorenz"));
+            }
+            options => panic!("{:?}", options),
+        }
+        match parse(&["lorenz", "encrypt", "-r", &public, "--hints", "f.txt", "-o", "x", "-r", "alice"]) {
+            Options::Encrypt { recipients, hints, file, output, .. } => {
+                assert_eq!(recipients, vec![public.clone(), "alice".to_string()]);
+                assert!(hints);
+                assert_eq!(file, PathBuf::from("f.txt"));
+                assert_eq!(output, Some(PathBuf::from("x")));
+            }
+            options => panic!("{:?}", options),
+        }
+        match parse(&["lorenz", "encrypt", "-r", &public, "--sender", &key, "f.txt"]) {
+            Options::Encrypt { recipients, sender: Some(_), file, .. } => {
+                assert_eq!(recipients, vec![public.clone()]);
+                assert_eq!(file, PathBuf::from("f.txt"));
+            }
+            options => panic!("{:?}", options),
+        }
+    }
+
+    #[test]
+    fn shares_take_one_file_each() {
+        match parse(&["lorenz", "decrypt", "--shares", "a.share", "f.lorenz", "--shares", "b.share"]) {
+            Options::Decrypt { file, shares, .. } => {
+                assert_eq!(file, PathBuf::from("f.lorenz"));
+                assert_eq!(shares, [PathBuf::from("a.share"), PathBuf::from("b.share")]);
+            }
+            options => panic!("{:?}", options),
+        }
+    }
+
+    #[test]
+    fn unwrap_share_key_sources() {
+        match parse(&["lorenz", "unwrap-share", "f.lorenz", "-k", "me", "--to", "bob"]) {
+            Options::UnwrapShare { key: KeySource { key: Some(key), .. }, file, combiner, .. } => {
+                assert_eq!(key, "me");
+                assert_eq!(file, PathBuf::from("f.lorenz"));
+                assert_eq!(combiner, "bob");
+            }
+            options => panic!("{:?}", options),
+        }
+        match parse(&["lorenz", "unwrap-share", "--ssh-key", "id_ed25519", "--to", "bob", "f.lorenz"]) {
+            Options::UnwrapShare { key: KeySource { ssh_key: Some(path), .. }, .. } => assert_eq!(path, PathBuf::from("id_ed25519")),
+            options => panic!("{:?}", options),
+        }
+    }
+
+    #[test]
+    fn rewrite_key_sources() {
+        let public = UserSecretKey::new().unwrap().get_public().to_string();
+        match parse(&["lorenz", "add-recipient", "-r", &public, "-k", "me", "f.lorenz", "-o", "g.lorenz", "-r", "@team"]) {
+            Options::AddRecipient { key: KeySource { key: Some(key), .. }, recipients, file, .. } => {
+                assert_eq!(key, "me");
+                assert_eq!(recipients, [public.clone(), "@team".to_string()]);
+                assert_eq!(file, PathBuf::from("f.lorenz"));
+            }
+            options => panic!("{:?}", options),
+        }
+        match parse(&["lorenz", "remove-recipient", "-s", "0", "--key-file", "my.lorenzkey", "f.lorenz", "--stanza", "1"]) {
+            Options::RemoveRecipient { key: KeySource { key_file: Some(path), .. }, stanzas, file, .. } => {
+                assert_eq!(path, PathBuf::from("my.lorenzkey"));
+                assert_eq!(stanzas.len(), 2);
+                assert_eq!(file, PathBuf::from("f.lorenz"));
+            }
+            options => panic!("{:?}", options),
+        }
+    }
+
+    #[test]
+    fn key_sources_conflict() {
+        assert!(Options::from_iter_safe(&["lorenz", "decrypt", "-k", "alice", "--passphrase", "f.lorenz"]).is_err());
+        assert!(Options::from_iter_safe(&["lorenz", "decrypt", "--key-file", "k", "--ssh-key", "s", "f.lorenz"]).is_err());
+        assert!(Options::from_iter_safe(&["lorenz", "encrypt", "f.txt"]).is_err());
     }
-    let hex_vec: Vec<u8> = hex.from_hex()?;
-    let mut result = [0u8; 32];
-    result.copy_from_slice(&hex_vec);
-    Ok(result)
 }
@@ -2,10 +2,9 @@ use crate::Error;
 use ring::aead::{self, open_in_place, seal_in_place, Aad, Nonce, OpeningKey, SealingKey, AES_256_GCM, CHACHA20_POLY1305, NONCE_LEN};
 use ring::rand::{SecureRandom, SystemRandom};
 use std::str::FromStr;
-use structopt::clap::{Error as ClapError, ErrorKind as ClapErrorKind};
 use zeroize::Zeroizing;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Scheme {
     AES256GCM,
     Chacha20Poly1305,
@@ -53,6 +52,22 @@ fn get_random_nonce() -> Result<(Nonce, [u8; 12]), Error> {
 }
 
 impl Scheme {
+    /// The identifier stored in the file header.
+    pub fn id(self) -> u8 {
+        match self {
+            Scheme::AES256GCM => 1,
+            Scheme::Chacha20Poly1305 => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Scheme::AES256GCM),
+            2 => Some(Scheme::Chacha20Poly1305),
+            _ => None,
+        }
+    }
+
     pub fn get_algorithm(self) -> &'static aead::Algorithm {
         match self {
             Scheme::AES256GCM => &AES_256_GCM,
@@ -67,16 +82,13 @@ impl Scheme {
 }
 
 impl FromStr for Scheme {
-    type Err = ClapError;
+    type Err = Error;
 
     fn from_str(mode: &str) -> Result<Self, Self::Err> {
         match mode.to_lowercase().as_str() {
             "aes" | "aes256" => Ok(Scheme::AES256GCM),
             "chacha" | "chacha20" | "chacha20poly1305" => Ok(Scheme::Chacha20Poly1305),
-            a => Err(ClapError::with_description(
-                &format!("{} Mode isn't supported, please choose one of these: AES/Chacha20", a),
-                ClapErrorKind::InvalidValue,
-            )),
+            a => Err(Error::UnknownScheme(a.to_owned())),
         }
     }
 }
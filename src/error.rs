@@ -1,9 +1,9 @@
+use crate::header;
 use rand_os::rand_core::Error as RandError;
 use ring::error::Unspecified as RingError;
 use std::error::Error as StdError;
 use std::fmt;
 use std::io;
-use structopt::clap::{Error as ClapError, ErrorKind as ClapErrorKind};
 
 #[derive(Debug)]
 pub enum Error {
@@ -12,6 +12,44 @@ pub enum Error {
     RingError,
     BadKeyLength,
     BadKey,
+    BadChecksum,
+    WrongKeyKind(&'static str, &'static str),
+    UntypedHexKey,
+    BadHeader,
+    TruncatedHeader,
+    MalformedHeader(&'static str),
+    HeaderMismatch,
+    BadStanza,
+    UnsupportedVersion(u8),
+    UnsupportedScheme(u8),
+    UnknownScheme(String),
+    UnsupportedFlags(u8),
+    Truncated,
+    CorruptedChunk(u64),
+    TooManyRecipients(usize),
+    NoRecipients,
+    NoSuchStanza(String),
+    BadKeyFile,
+    BadPassphrase,
+    BadWorkFactor(u8),
+    BadSignature,
+    BadSender,
+    SenderWithManyRecipients(usize),
+    BadKeyName(String),
+    KeyNameTaken(String),
+    UnknownKeyName(String),
+    UnknownGroup(String),
+    BadMnemonic(String),
+    BadDerivationLabel(String),
+    BadThreshold(u8, usize),
+    NotEnoughShares(usize, u8),
+    NotAShareHolder,
+    BadShareFile,
+    UnknownFingerprint(String),
+    AmbiguousFingerprint(String),
+    BadSshKey(&'static str),
+    UnsupportedSshKey(String),
+    SshKeyEncrypted,
 }
 
 impl Error {
@@ -21,6 +59,25 @@ impl Error {
     pub fn bad_key<E>(_: E) -> Self {
         Error::BadKey
     }
+    pub fn bad_header<E>(_: E) -> Self {
+        Error::BadHeader
+    }
+    /// A header that ends early is reported as truncated, other IO errors are kept as is.
+    pub fn truncated_header(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => Error::TruncatedHeader,
+            _ => err.into(),
+        }
+    }
+    pub fn truncated<E>(_: E) -> Self {
+        Error::Truncated
+    }
+    pub fn bad_key_file<E>(_: E) -> Self {
+        Error::BadKeyFile
+    }
+    pub fn bad_passphrase<E>(_: E) -> Self {
+        Error::BadPassphrase
+    }
 }
 
 impl StdError for Error {}
@@ -34,16 +91,86 @@ impl fmt::Display for Error {
             RingError => writeln!(f, "Lorenz Error: Failed Encrypting/Decrypting data"),
             BadKeyLength => writeln!(f, "Lorenz Error: Key isn't the right length"),
             BadKey => writeln!(f, "Lorenz Error: Couldn't find the right key"),
+            BadChecksum => writeln!(f, "Lorenz Error: The key's checksum doesn't match, it was probably mistyped"),
+            WrongKeyKind(expected, found) => writeln!(f, "Lorenz Error: Expected a {} but got a {}", expected, found),
+            UntypedHexKey => writeln!(
+                f,
+                "Lorenz Error: Hex keys don't say what kind of key they are, convert it first with `lorenz key from-hex <kind> <key>`"
+            ),
+            BadHeader => writeln!(f, "Lorenz Error: Not a lorenz file or the header is corrupted"),
+            TruncatedHeader => writeln!(f, "Lorenz Error: The file header is truncated"),
+            MalformedHeader(reason) => writeln!(f, "Lorenz Error: Malformed file header: {}", reason),
+            HeaderMismatch => writeln!(f, "Lorenz Error: The file header was modified"),
+            BadStanza => writeln!(f, "Lorenz Error: The recipient's wrapped key is corrupted"),
+            UnsupportedVersion(v) => writeln!(f, "Lorenz Error: Unsupported file format version: {}", v),
+            UnsupportedScheme(s) => writeln!(f, "Lorenz Error: Unsupported encryption scheme id: {}", s),
+            UnknownScheme(s) => writeln!(f, "Lorenz Error: {} Mode isn't supported, please choose one of these: AES/Chacha20", s),
+            UnsupportedFlags(flags) => writeln!(f, "Lorenz Error: Unsupported header flags: {:#04x}", flags),
+            Truncated => writeln!(f, "Lorenz Error: The encrypted data is truncated"),
+            CorruptedChunk(i) => writeln!(f, "Lorenz Error: Chunk {} of the encrypted data is corrupted or out of order", i),
+            NoRecipients => writeln!(f, "Lorenz Error: At least one recipient is required"),
+            NoSuchStanza(stanza) => writeln!(f, "Lorenz Error: No stanza {} in this file's header", stanza),
+            BadKeyFile => writeln!(f, "Lorenz Error: Not a lorenz key file or the key file is corrupted"),
+            BadPassphrase => writeln!(f, "Lorenz Error: Wrong passphrase"),
+            BadWorkFactor(w) => writeln!(f, "Lorenz Error: Unsupported scrypt work factor: {}", w),
+            BadSender => writeln!(f, "Lorenz Error: The file wasn't encrypted to this key by the expected sender"),
+            SenderWithManyRecipients(n) => {
+                writeln!(f, "Lorenz Error: A file authenticated by its sender must have a single recipient, not {}", n)
+            }
+            BadKeyName(name) => writeln!(f, "Lorenz Error: `{}` isn't a valid key name, use letters, digits, `-`, `_` and `.`", name),
+            KeyNameTaken(name) => writeln!(f, "Lorenz Error: There's already a key named `{}` in the keyring", name),
+            UnknownKeyName(name) => writeln!(f, "Lorenz Error: There's no key named `{}` in the keyring", name),
+            UnknownGroup(name) => writeln!(f, "Lorenz Error: There's no group named `@{}` in the keyring", name),
+            BadMnemonic(reason) => writeln!(f, "Lorenz Error: Invalid mnemonic: {}", reason),
+            BadThreshold(k, n) => {
+                writeln!(
+                    f,
+                    "Lorenz Error: A threshold of {} with {} share holders, it must be between 2 and the number of share holders",
+                    k, n
+                )
+            }
+            NotAShareHolder => writeln!(f, "Lorenz Error: The key doesn't hold a share of this file's key"),
+            BadShareFile => writeln!(f, "Lorenz Error: Not a lorenz share file or the share file is corrupted"),
+            NotEnoughShares(have, need) => {
+                writeln!(f, "Lorenz Error: Only {} of the {} shares needed to decrypt were found", have, need)
+            }
+            BadDerivationLabel(label) => {
+                writeln!(f, "Lorenz Error: `{}` isn't a valid derivation label, use `/` between non empty components", label)
+            }
+            UnknownFingerprint(id) => writeln!(f, "Lorenz Error: There's no key with the fingerprint `{}` in the keyring", id),
+            AmbiguousFingerprint(id) => {
+                writeln!(f, "Lorenz Error: More than one key in the keyring matches `{}`, use the full fingerprint", id)
+            }
+            BadSshKey(reason) => writeln!(f, "Lorenz Error: Invalid SSH key: {}", reason),
+            UnsupportedSshKey(kind) => {
+                writeln!(f, "Lorenz Error: Unsupported SSH key type `{}`, only ssh-ed25519 keys are supported", kind)
+            }
+            SshKeyEncrypted => writeln!(f, "Lorenz Error: The SSH key is encrypted, a passphrase is required"),
+            BadSignature => writeln!(f, "Lorenz Error: The file isn't signed by the expected signer, or its signature is invalid"),
+            TooManyRecipients(n) => writeln!(f, "Lorenz Error: Too many recipients: {}, the maximum is {}", n, header::MAX_RECIPIENTS),
         }
     }
 }
 
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Self {
+        // Unwrap errors that were passed through an `io::Read`/`io::Write` implementation.
+        if err.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            return *err.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
         Error::IoError(err)
     }
 }
 
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::IoError(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
 impl From<RandError> for Error {
     fn from(err: RandError) -> Self {
         Error::RngError(err)
@@ -55,14 +182,3 @@ impl From<RingError> for Error {
         Error::RingError
     }
 }
-
-impl From<Error> for ClapError {
-    fn from(err: Error) -> ClapError {
-        use Error::*;
-        match err {
-            IoError(e) => e.into(),
-            RngError(e) => ClapError::with_description(e.description(), ClapErrorKind::Io),
-            _ => unimplemented!(),
-        }
-    }
-}
@@ -0,0 +1,96 @@
+use ring::digest::{Context, SHA256};
+use rustc_hex::ToHex;
+use std::fmt;
+
+const FINGERPRINT_LABEL: &[u8] = b"lorenz-fingerprint";
+pub const FINGERPRINT_LEN: usize = 16;
+/// A short ID is the first bytes of a fingerprint, enough to tell the keys of a keyring apart.
+pub const SHORT_ID_LEN: usize = 8;
+
+/// A hash of a public key that's short enough to be compared over the phone.
+/// Displayed as groups of 4 hex digits.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Fingerprint([u8; FINGERPRINT_LEN]);
+
+impl Fingerprint {
+    /// The fingerprint of a public key's encoding.
+    pub(crate) fn of(key: &[u8]) -> Self {
+        let mut ctx = Context::new(&SHA256);
+        ctx.update(FINGERPRINT_LABEL);
+        ctx.update(key);
+        let mut fingerprint = [0u8; FINGERPRINT_LEN];
+        fingerprint.copy_from_slice(&ctx.finish().as_ref()[..FINGERPRINT_LEN]);
+        Fingerprint(fingerprint)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; FINGERPRINT_LEN] {
+        &self.0
+    }
+
+    pub fn short_id(&self) -> String {
+        self.0[..SHORT_ID_LEN].to_hex()
+    }
+
+    /// Whether `id` is this fingerprint, or a prefix of it at least as long as a short ID.
+    /// Spaces and colons in `id` are ignored.
+    pub fn matches(&self, id: &str) -> bool {
+        match normalize(id) {
+            Some(id) => self.0.to_hex::<String>().starts_with(&id),
+            None => false,
+        }
+    }
+}
+
+/// Whether `id` looks like a fingerprint or a short ID, rather than a key or a name.
+pub fn is_fingerprint(id: &str) -> bool {
+    normalize(id).is_some()
+}
+
+fn normalize(id: &str) -> Option<String> {
+    let id = id.chars().filter(|&c| c != ' ' && c != ':').collect::<String>().to_ascii_lowercase();
+    let valid_len = SHORT_ID_LEN * 2 <= id.len() && id.len() <= FINGERPRINT_LEN * 2;
+    if valid_len && id.bytes().all(|b| b.is_ascii_hexdigit()) {
+        Some(id)
+    } else {
+        None
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let groups: Vec<String> = self.0.chunks(2).map(|group| group.to_hex()).collect();
+        f.write_str(&groups.join(" "))
+    }
+}
+
+impl fmt::Debug for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Fingerprint({})", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::generate_random_keys;
+
+    #[test]
+    fn fingerprints() {
+        let keys = generate_random_keys(2);
+        let fingerprint = keys[0].1.fingerprint();
+        assert_eq!(fingerprint, keys[0].0.get_public().fingerprint());
+        assert_ne!(fingerprint, keys[1].1.fingerprint());
+
+        let displayed = fingerprint.to_string();
+        assert_eq!(displayed.len(), FINGERPRINT_LEN * 2 + FINGERPRINT_LEN / 2 - 1);
+        assert!(fingerprint.matches(&displayed));
+        assert!(fingerprint.matches(&displayed.to_uppercase().replace(' ', ":")));
+        assert!(fingerprint.matches(&fingerprint.short_id()));
+        assert!(!fingerprint.matches(&fingerprint.short_id()[..SHORT_ID_LEN * 2 - 1]));
+        assert!(!keys[1].1.fingerprint().matches(&displayed));
+
+        assert!(is_fingerprint(&fingerprint.short_id()));
+        assert!(!is_fingerprint("alice"));
+        assert!(!is_fingerprint(&keys[0].1.as_bytes().to_hex::<String>()));
+    }
+}
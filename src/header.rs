@@ -0,0 +1,352 @@
+use crate::encryption::Scheme;
+use crate::{Error, Secret};
+use ring::digest::SHA256;
+use ring::hkdf;
+use ring::hmac::{self, SigningKey};
+use std::io::{Read, Write};
+use x25519_dalek::PublicKey;
+
+pub const MAGIC: &[u8; 6] = b"LORENZ";
+pub const VERSION: u8 = 1;
+pub const MAC_LEN: usize = 32;
+
+/// The maximum amount of recipients a single file can be encrypted to.
+pub const MAX_RECIPIENTS: usize = u16::MAX as usize;
+
+/// The maximum size of a single stanza body.
+pub const MAX_STANZA_LEN: usize = 64 * 1024;
+
+/// A file key wrapped to an X25519 public key, with the shared secret from the header's ephemeral key.
+/// Body: `recipient hint (only with FLAG_RECIPIENT_HINTS) | encrypted file key`.
+pub const STANZA_X25519: u8 = 1;
+/// A file key wrapped under a passphrase, a file has at most one of them.
+/// Body: `scrypt work factor | salt | encrypted file key`.
+pub const STANZA_PASSPHRASE: u8 = 2;
+/// Like `STANZA_X25519`, but the wrapping key also depends on the static key of the sender,
+/// so decrypting it proves the file was encrypted by the holder of that key. Only valid as the single stanza of a header.
+/// Body: same as `STANZA_X25519`.
+pub const STANZA_X25519_AUTHENTICATED: u8 = 3;
+/// A Shamir share of the file key wrapped to an X25519 public key, any `threshold` of them recover the file key.
+/// Body: `recipient hint (only with FLAG_RECIPIENT_HINTS) | threshold | share index | encrypted share`.
+pub const STANZA_X25519_SHARE: u8 = 4;
+/// A file key wrapped to a hybrid X25519 + ML-KEM-768 public key, the wrapping key depends on both shared secrets
+/// so it stays safe as long as either of them is unbroken. The hint comes from the X25519 shared secret alone.
+/// Body: `recipient hint (only with FLAG_RECIPIENT_HINTS) | ML-KEM ciphertext | encrypted file key`.
+pub const STANZA_X25519_HYBRID: u8 = 5;
+/// A stanza added to an existing file by `Decryptor::add_recipients`. The secret of the header's ephemeral key
+/// isn't kept anywhere, so the inner stanza is wrapped with an ephemeral key of its own instead.
+/// Body: `ephemeral public key | inner stanza kind | inner stanza body`.
+pub const STANZA_ADDED: u8 = 6;
+
+/// X25519 stanzas start with a short tag derived from the shared secret,
+/// so a recipient can find its stanza without trying to decrypt every one of them.
+pub const FLAG_RECIPIENT_HINTS: u8 = 1;
+pub const HINT_LEN: usize = 8;
+
+/// The plaintext ends with an Ed25519 signature over the header and the plaintext.
+pub const FLAG_SIGNED: u8 = 2;
+
+/// Any other set bit means the file was written by a newer version.
+const KNOWN_FLAGS: u8 = FLAG_RECIPIENT_HINTS | FLAG_SIGNED;
+
+/// The file key wrapped for a single recipient.
+/// Stanzas of an unknown kind are skipped when decrypting, so new recipient types can be added.
+///
+/// Layout: `kind | body length (varint) | body`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stanza {
+    pub kind: u8,
+    pub body: Vec<u8>,
+}
+
+/// The header written at the start of every `.lorenz` file.
+/// The MAC is keyed from the file key, so any modification of the header is detected by recipients.
+///
+/// Layout: `MAGIC | version | scheme id | flags | ephemeral public key | stanzas count (varint) | stanzas | MAC`.
+pub struct Header {
+    pub scheme: Scheme,
+    pub flags: u8,
+    pub ephemeral: PublicKey,
+    pub stanzas: Vec<Stanza>,
+    pub mac: [u8; MAC_LEN],
+}
+
+impl Stanza {
+    pub fn new(kind: u8, body: Vec<u8>) -> Self {
+        Stanza { kind, body }
+    }
+
+    fn write<W: Write>(&self, output: &mut W) -> Result<(), Error> {
+        if self.body.len() > MAX_STANZA_LEN {
+            return Err(Error::BadHeader);
+        }
+        output.write_all(&[self.kind])?;
+        write_varint(output, self.body.len() as u64)?;
+        output.write_all(&self.body)?;
+        Ok(())
+    }
+
+    /// Wraps `inner` into a `STANZA_ADDED` stanza, `ephemeral` is the public key it was wrapped with.
+    pub fn added(ephemeral: &PublicKey, inner: &Stanza) -> Self {
+        let mut body = ephemeral.as_bytes().to_vec();
+        body.push(inner.kind);
+        body.extend_from_slice(&inner.body);
+        Stanza::new(STANZA_ADDED, body)
+    }
+
+    /// The ephemeral public key and the inner stanza of a `STANZA_ADDED` stanza.
+    pub fn unwrap_added(&self) -> Option<(PublicKey, Stanza)> {
+        if self.kind != STANZA_ADDED || self.body.len() < 33 {
+            return None;
+        }
+        let mut ephemeral = [0u8; 32];
+        ephemeral.copy_from_slice(&self.body[..32]);
+        Some((ephemeral.into(), Stanza::new(self.body[32], self.body[33..].to_vec())))
+    }
+
+    fn read<R: Read>(input: &mut R) -> Result<Self, Error> {
+        let mut kind = [0u8];
+        input.read_exact(&mut kind).map_err(Error::truncated_header)?;
+        let len = read_varint(input)?;
+        if len > MAX_STANZA_LEN as u64 {
+            return Err(Error::MalformedHeader("a stanza is too long"));
+        }
+        let mut body = vec![0u8; len as usize];
+        input.read_exact(&mut body).map_err(Error::truncated_header)?;
+        Ok(Stanza { kind: kind[0], body })
+    }
+}
+
+impl Header {
+    pub fn new(scheme: Scheme, ephemeral: PublicKey) -> Self {
+        Header { scheme, flags: 0, ephemeral, stanzas: Vec::new(), mac: [0u8; MAC_LEN] }
+    }
+
+    /// Every stanza added by `Decryptor::add_recipients` as a header of its own, with the ephemeral key it was wrapped with,
+    /// so identities can look for their stanzas in them the same way as in the header itself.
+    pub fn added_headers(&self) -> Vec<Header> {
+        self.stanzas
+            .iter()
+            .filter_map(Stanza::unwrap_added)
+            .map(|(ephemeral, inner)| Header {
+                scheme: self.scheme,
+                flags: self.flags,
+                ephemeral,
+                stanzas: vec![inner],
+                mac: [0u8; MAC_LEN],
+            })
+            .collect()
+    }
+
+    pub fn write<W: Write>(&self, output: &mut W) -> Result<(), Error> {
+        output.write_all(&self.authenticated_bytes()?)?;
+        output.write_all(&self.mac)?;
+        Ok(())
+    }
+
+    /// The size of the header as written by `write`.
+    pub fn encoded_len(&self) -> Result<usize, Error> {
+        Ok(self.authenticated_bytes()?.len() + MAC_LEN)
+    }
+
+    /// Computes the MAC over the header, must be called after all the stanzas were added.
+    pub fn seal(&mut self, file_key: &Secret) -> Result<(), Error> {
+        let tag = hmac::sign(&mac_key(file_key), &self.authenticated_bytes()?);
+        self.mac.copy_from_slice(tag.as_ref());
+        Ok(())
+    }
+
+    /// Checks that the header wasn't modified since it was sealed with `file_key`.
+    pub fn verify(&self, file_key: &Secret) -> Result<(), Error> {
+        let bytes = self.authenticated_bytes()?;
+        hmac::verify_with_own_key(&mac_key(file_key), &bytes, &self.mac).map_err(|_| Error::HeaderMismatch)
+    }
+
+    /// The part of the header before the stanzas, it stays the same when recipients are added or removed.
+    /// Signatures cover these bytes, they're unique to the file thanks to the ephemeral key.
+    pub fn fixed_bytes(&self) -> Vec<u8> {
+        let mut output = Vec::new();
+        output.extend_from_slice(MAGIC);
+        output.extend_from_slice(&[VERSION, self.scheme.id(), self.flags]);
+        output.extend_from_slice(self.ephemeral.as_bytes());
+        output
+    }
+
+    /// Everything in the header except the MAC itself.
+    /// Every field has a single encoding, so a parsed header serializes back into the exact same bytes.
+    fn authenticated_bytes(&self) -> Result<Vec<u8>, Error> {
+        if self.stanzas.len() > MAX_RECIPIENTS {
+            return Err(Error::TooManyRecipients(self.stanzas.len()));
+        }
+        let mut output = self.fixed_bytes();
+        write_varint(&mut output, self.stanzas.len() as u64)?;
+        for stanza in &self.stanzas {
+            stanza.write(&mut output)?;
+        }
+        Ok(output)
+    }
+
+    pub fn read<R: Read>(input: &mut R) -> Result<Self, Error> {
+        let mut magic = [0u8; 6];
+        input.read_exact(&mut magic).map_err(Error::bad_header)?;
+        if &magic != MAGIC {
+            return Err(Error::BadHeader);
+        }
+        let mut preamble = [0u8; 3];
+        input.read_exact(&mut preamble).map_err(Error::truncated_header)?;
+        let [version, scheme, flags] = preamble;
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let scheme = Scheme::from_id(scheme).ok_or(Error::UnsupportedScheme(scheme))?;
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(Error::UnsupportedFlags(flags));
+        }
+
+        let mut ephemeral = [0u8; 32];
+        input.read_exact(&mut ephemeral).map_err(Error::truncated_header)?;
+        let amount = read_varint(input)?;
+        if amount > MAX_RECIPIENTS as u64 {
+            return Err(Error::TooManyRecipients(amount as usize));
+        }
+
+        let mut stanzas = Vec::with_capacity(amount as usize);
+        for _ in 0..amount {
+            stanzas.push(Stanza::read(input)?);
+        }
+        // Every passphrase stanza costs a full scrypt run to try, more than one would let a file make decrypting arbitrarily slow.
+        if stanzas.iter().filter(|s| s.kind == STANZA_PASSPHRASE).count() > 1 {
+            return Err(Error::MalformedHeader("more than one passphrase stanza"));
+        }
+        let mut mac = [0u8; MAC_LEN];
+        input.read_exact(&mut mac).map_err(Error::truncated_header)?;
+
+        Ok(Header { scheme, flags, ephemeral: ephemeral.into(), stanzas, mac })
+    }
+}
+
+fn mac_key(file_key: &Secret) -> SigningKey {
+    let mut key = Secret::from_vec(vec![0u8; MAC_LEN]);
+    hkdf::extract_and_expand(&SigningKey::new(&SHA256, &[]), file_key.as_ref(), b"header", key.as_mut());
+    SigningKey::new(&SHA256, key.as_ref())
+}
+
+/// Writes `value` as an unsigned LEB128 varint.
+pub fn write_varint<W: Write>(output: &mut W, mut value: u64) -> Result<(), Error> {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            output.write_all(&[byte])?;
+            return Ok(());
+        }
+        output.write_all(&[byte | 0x80])?;
+    }
+}
+
+/// Reads an unsigned LEB128 varint, rejecting overlong encodings so every value has exactly one encoding.
+pub fn read_varint<R: Read>(input: &mut R) -> Result<u64, Error> {
+    let mut value = 0u64;
+    for i in 0..10 {
+        let mut byte = [0u8];
+        input.read_exact(&mut byte).map_err(Error::truncated_header)?;
+        let byte = byte[0];
+        if i == 9 && byte > 1 {
+            return Err(Error::MalformedHeader("a varint is too large"));
+        }
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            if byte == 0 && i != 0 {
+                return Err(Error::MalformedHeader("a varint isn't minimally encoded"));
+            }
+            return Ok(value);
+        }
+    }
+    Err(Error::MalformedHeader("a varint is too large"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x25519::EphemeralKey;
+    use std::io::Cursor;
+
+    fn header_bytes(scheme: Scheme) -> Vec<u8> {
+        let ephemeral = EphemeralKey::new().unwrap();
+        let mut header = Header::new(scheme, ephemeral.get_public());
+        header.stanzas.push(Stanza::new(STANZA_X25519, vec![7u8; scheme.get_encrypted_key_size()]));
+        header.seal(&Secret::from_vec(vec![1u8; 32])).unwrap();
+        let mut bytes = Vec::new();
+        header.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn header_roundtrip() {
+        for &scheme in &[Scheme::AES256GCM, Scheme::Chacha20Poly1305] {
+            let bytes = header_bytes(scheme);
+            let header = Header::read(&mut Cursor::new(&bytes)).unwrap();
+            assert_eq!(header.scheme, scheme);
+            assert_eq!(header.stanzas, vec![Stanza::new(STANZA_X25519, vec![7u8; scheme.get_encrypted_key_size()])]);
+            header.verify(&Secret::from_vec(vec![1u8; 32])).unwrap();
+            assert!(matches!(header.verify(&Secret::from_vec(vec![2u8; 32])), Err(Error::HeaderMismatch)));
+        }
+    }
+
+    #[test]
+    fn header_rejects_garbage() {
+        let mut bytes = header_bytes(Scheme::AES256GCM);
+        bytes[0] = b'X';
+        assert!(matches!(Header::read(&mut Cursor::new(&bytes)), Err(Error::BadHeader)));
+
+        let mut bytes = header_bytes(Scheme::AES256GCM);
+        bytes[MAGIC.len()] = VERSION + 1;
+        assert!(matches!(Header::read(&mut Cursor::new(&bytes)), Err(Error::UnsupportedVersion(_))));
+
+        let mut bytes = header_bytes(Scheme::AES256GCM);
+        bytes[MAGIC.len() + 1] = 0xff;
+        assert!(matches!(Header::read(&mut Cursor::new(&bytes)), Err(Error::UnsupportedScheme(0xff))));
+
+        let mut bytes = header_bytes(Scheme::AES256GCM);
+        bytes[MAGIC.len() + 2] = 0x80;
+        assert!(matches!(Header::read(&mut Cursor::new(&bytes)), Err(Error::UnsupportedFlags(0x80))));
+
+        let bytes = header_bytes(Scheme::AES256GCM);
+        assert!(matches!(Header::read(&mut Cursor::new(&bytes[..bytes.len() - 1])), Err(Error::TruncatedHeader)));
+
+        let mut bytes = header_bytes(Scheme::AES256GCM);
+        let count = MAGIC.len() + 3 + 32;
+        bytes.splice(count..count + 1, vec![0x80, 0x80, 0x04]);
+        assert!(matches!(Header::read(&mut Cursor::new(&bytes)), Err(Error::TooManyRecipients(0x10000))));
+
+        let mut bytes = header_bytes(Scheme::AES256GCM);
+        bytes.splice(count + 2..count + 3, vec![0x81, 0x80, 0x04]);
+        assert!(matches!(Header::read(&mut Cursor::new(&bytes)), Err(Error::MalformedHeader(_))));
+
+        let ephemeral = EphemeralKey::new().unwrap();
+        let mut header = Header::new(Scheme::AES256GCM, ephemeral.get_public());
+        header.stanzas = vec![Stanza::new(STANZA_PASSPHRASE, vec![1, 2, 3]); 2];
+        let mut bytes = Vec::new();
+        header.write(&mut bytes).unwrap();
+        assert!(matches!(Header::read(&mut Cursor::new(&bytes)), Err(Error::MalformedHeader("more than one passphrase stanza"))));
+    }
+
+    #[test]
+    fn varint_roundtrip() {
+        for &value in &[0, 1, 127, 128, 300, 16383, 16384, u64::from(u32::MAX), u64::MAX] {
+            let mut bytes = Vec::new();
+            write_varint(&mut bytes, value).unwrap();
+            assert_eq!(read_varint(&mut Cursor::new(&bytes)).unwrap(), value);
+        }
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, 300).unwrap();
+        assert_eq!(bytes, [0xac, 0x02]);
+    }
+
+    #[test]
+    fn varint_rejects_non_canonical() {
+        assert!(read_varint(&mut Cursor::new(&[0x81, 0x00])).is_err());
+        assert!(read_varint(&mut Cursor::new(&[0xff; 10])).is_err());
+        assert!(read_varint(&mut Cursor::new(&[0x80])).is_err());
+    }
+}
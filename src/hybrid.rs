@@ -0,0 +1,243 @@
+//! Hybrid recipients combine X25519 with the post-quantum ML-KEM-768 (FIPS 203),
+//! so files stay confidential as long as either of the two holds, even against a future quantum computer.
+
+use crate::bech32::{self, HYBRID_PUBLIC_KEY_HRP, HYBRID_SECRET_KEY_HRP};
+use crate::encryption;
+use crate::fingerprint::Fingerprint;
+use crate::header::{self, Stanza};
+use crate::recipient::{decrypt_file_key, recipient_hint, Identity, Recipient, UnwrapContext, WrapContext};
+use crate::x25519::{EphemeralKey, UserPublicKey, UserSecretKey, SALT};
+use crate::{Error, Secret};
+use ml_kem::kem::{Decapsulate, DecapsulationKey, EncapsulationKey};
+use ml_kem::{Ciphertext, EncapsulateDeterministic, Encoded, EncodedSizeUser, KemCore, MlKem768, MlKem768Params, B32};
+use ring::hkdf;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use x25519_dalek::PublicKey;
+use zeroize::{Zeroize, Zeroizing};
+
+const X25519_INFO: &[u8] = b"lorenz-hybrid-x25519";
+const MLKEM_INFO: &[u8] = b"lorenz-hybrid-mlkem768";
+const WRAPPING_INFO: &[u8] = b"lorenz-hybrid-x25519-mlkem768";
+const SEED_LEN: usize = 32;
+const MLKEM_PUBLIC_KEY_LEN: usize = 1184;
+const MLKEM_CIPHERTEXT_LEN: usize = 1088;
+
+type MlKemDecapsulationKey = DecapsulationKey<MlKem768Params>;
+type MlKemEncapsulationKey = EncapsulationKey<MlKem768Params>;
+
+/// Derives the wrapping key from both shared secrets, along with both X25519 public keys
+/// since the X25519 shared secret alone doesn't commit to them.
+fn derive_wrapping_key(mlkem_shared: &[u8], x25519_shared: &[u8; 32], ephemeral: &PublicKey, recipient: &UserPublicKey) -> Secret {
+    let mut ikm = Zeroizing::new(Vec::with_capacity(mlkem_shared.len() + 96));
+    ikm.extend_from_slice(mlkem_shared);
+    ikm.extend_from_slice(x25519_shared);
+    ikm.extend_from_slice(ephemeral.as_bytes());
+    ikm.extend_from_slice(recipient.as_bytes());
+    let mut key = Secret::from_vec(vec![0u8; 32]);
+    hkdf::extract_and_expand(&SALT, &ikm, WRAPPING_INFO, key.as_mut());
+    key
+}
+
+/// A private key of a hybrid recipient, its X25519 and ML-KEM halves are both derived from a single seed.
+pub struct HybridSecretKey(Zeroizing<[u8; SEED_LEN]>);
+
+impl HybridSecretKey {
+    pub fn new() -> Result<Self, Error> {
+        let mut seed = Zeroizing::new([0u8; SEED_LEN]);
+        SystemRandom::new().fill(&mut seed[..])?;
+        Ok(HybridSecretKey(seed))
+    }
+
+    pub fn get_public(&self) -> HybridPublicKey {
+        let (_, mlkem) = self.mlkem();
+        HybridPublicKey { x25519: self.x25519().get_public(), mlkem: mlkem.as_bytes().to_vec() }
+    }
+
+    /// The X25519 half, recipient hints are derived from it alone so they can be checked without decapsulating.
+    pub(crate) fn x25519(&self) -> UserSecretKey {
+        let mut secret = Zeroizing::new([0u8; 32]);
+        hkdf::extract_and_expand(&SALT, &self.0[..], X25519_INFO, &mut secret[..]);
+        UserSecretKey::from_bytes(&secret)
+    }
+
+    fn mlkem(&self) -> (MlKemDecapsulationKey, MlKemEncapsulationKey) {
+        let mut seeds = Zeroizing::new([0u8; 64]);
+        hkdf::extract_and_expand(&SALT, &self.0[..], MLKEM_INFO, &mut seeds[..]);
+        let (d, z) = seeds.split_at(32);
+        let (mut d, mut z) = (B32::try_from(d).expect("32 bytes"), B32::try_from(z).expect("32 bytes"));
+        let keys = MlKem768::generate_deterministic(&d, &z);
+        d[..].zeroize();
+        z[..].zeroize();
+        keys
+    }
+
+    /// The wrapping key of a stanza from its ML-KEM ciphertext and the header's ephemeral key.
+    /// A ciphertext made for another key gives an unrelated wrapping key rather than an error.
+    fn derive_wrapping_key(&self, ephemeral: &PublicKey, ciphertext: &[u8]) -> Option<Secret> {
+        let ciphertext = Ciphertext::<MlKem768>::try_from(ciphertext).ok()?;
+        let (mlkem, _) = self.mlkem();
+        let mut mlkem_shared = mlkem.decapsulate(&ciphertext).ok()?;
+        let x25519 = self.x25519();
+        let key = derive_wrapping_key(&mlkem_shared, &x25519.diffie_hellman(ephemeral), ephemeral, &x25519.get_public());
+        mlkem_shared[..].zeroize();
+        Some(key)
+    }
+}
+
+#[cfg(test)]
+impl Clone for HybridSecretKey {
+    fn clone(&self) -> Self {
+        HybridSecretKey(Zeroizing::new(*self.0))
+    }
+}
+
+/// The public key of a hybrid recipient, files encrypted to it can be decrypted with the matching `HybridSecretKey`.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct HybridPublicKey {
+    x25519: UserPublicKey,
+    mlkem: Vec<u8>,
+}
+
+impl HybridPublicKey {
+    pub fn fingerprint(&self) -> Fingerprint {
+        Fingerprint::of(&self.to_bytes())
+    }
+
+    /// Encapsulates a fresh ML-KEM shared secret, and returns its ciphertext along with the wrapping key for this recipient.
+    fn derive_wrapping_key(&self, ephemeral: &EphemeralKey) -> Result<(Vec<u8>, Secret), Error> {
+        let encoded = Encoded::<MlKemEncapsulationKey>::try_from(&self.mlkem[..]).expect("the key was checked when parsed");
+        let mut randomness = Zeroizing::new([0u8; 32]);
+        SystemRandom::new().fill(&mut randomness[..])?;
+        let mut randomness = B32::from(*randomness);
+        let encapsulated = MlKemEncapsulationKey::from_bytes(&encoded).encapsulate_deterministic(&randomness);
+        randomness[..].zeroize();
+        let (ciphertext, mut mlkem_shared) = encapsulated.map_err(|_| Error::BadKey)?;
+        let x25519_shared = ephemeral.diffie_hellman(&self.x25519.0);
+        let key = derive_wrapping_key(&mlkem_shared, &x25519_shared, &ephemeral.get_public(), &self.x25519);
+        mlkem_shared[..].zeroize();
+        Ok((ciphertext.to_vec(), key))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        [&self.x25519.as_bytes()[..], &self.mlkem].concat()
+    }
+}
+
+impl Recipient for HybridPublicKey {
+    fn fingerprint(&self) -> Option<Fingerprint> {
+        Some(HybridPublicKey::fingerprint(self))
+    }
+
+    /// The hint comes from the X25519 shared secret, so it's as cheap to check as any other.
+    fn wrap_file_key(&self, file_key: &Secret, ctx: &WrapContext) -> Result<Stanza, Error> {
+        let mut body = if ctx.hints { recipient_hint(&ctx.ephemeral.derive_secret(&self.x25519.0, 32)).to_vec() } else { Vec::new() };
+        let (ciphertext, wrapping_key) = self.derive_wrapping_key(ctx.ephemeral)?;
+        body.extend(ciphertext);
+        body.extend(encryption::encrypt_data(wrapping_key.as_ref(), file_key.clone().into_vec(), ctx.scheme)?);
+        Ok(Stanza::new(header::STANZA_X25519_HYBRID, body))
+    }
+}
+
+impl Identity for HybridSecretKey {
+    fn unwrap_file_key(&self, ctx: &UnwrapContext) -> Result<Option<Secret>, Error> {
+        let hint = recipient_hint(&self.x25519().derive_secret(ctx.ephemeral(), 32));
+        let scheme = ctx.scheme();
+        ctx.unwrap_stanzas(header::STANZA_X25519_HYBRID, &hint, |body| {
+            if body.len() <= MLKEM_CIPHERTEXT_LEN {
+                return None;
+            }
+            let (ciphertext, encrypted_key) = body.split_at(MLKEM_CIPHERTEXT_LEN);
+            decrypt_file_key(&self.derive_wrapping_key(ctx.ephemeral(), ciphertext)?, encrypted_key, scheme)
+        })
+    }
+}
+
+impl fmt::Display for HybridPublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&bech32::encode(HYBRID_PUBLIC_KEY_HRP, &self.to_bytes()))
+    }
+}
+
+impl fmt::Debug for HybridPublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HybridPublicKey({})", self.fingerprint())
+    }
+}
+
+impl FromStr for HybridPublicKey {
+    type Err = Error;
+
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        let bytes = bech32::decode_key_bytes(key, HYBRID_PUBLIC_KEY_HRP, 32 + MLKEM_PUBLIC_KEY_LEN)?;
+        let (x25519, mlkem) = bytes.split_at(32);
+        // FIPS 203 requires every coefficient to be reduced, which is exactly when the key encodes back into the same bytes.
+        let encoded = Encoded::<MlKemEncapsulationKey>::try_from(mlkem).map_err(|_| Error::BadKeyLength)?;
+        if MlKemEncapsulationKey::from_bytes(&encoded).as_bytes() != encoded {
+            return Err(Error::BadKey);
+        }
+        let mut x25519_bytes = [0u8; 32];
+        x25519_bytes.copy_from_slice(x25519);
+        Ok(HybridPublicKey { x25519: UserPublicKey::from(x25519_bytes), mlkem: mlkem.to_vec() })
+    }
+}
+
+impl fmt::Display for HybridSecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "privateKey: {}", *bech32::encode(HYBRID_SECRET_KEY_HRP, &self.0[..]))?;
+        writeln!(f, "publicKey: {}", self.get_public())?;
+        writeln!(f, "fingerprint: {}", self.get_public().fingerprint())
+    }
+}
+
+impl fmt::Debug for HybridSecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl FromStr for HybridSecretKey {
+    type Err = Error;
+
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        bech32::decode_key(key, HYBRID_SECRET_KEY_HRP).map(HybridSecretKey)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hybrid_keys() {
+        let key = HybridSecretKey::new().unwrap();
+        let public = key.get_public();
+        let parsed: HybridPublicKey = public.to_string().parse().unwrap();
+        assert_eq!(parsed, public);
+        let secret = key.to_string();
+        let secret = secret.lines().next().unwrap().trim_start_matches("privateKey: ");
+        assert_eq!(secret.parse::<HybridSecretKey>().unwrap().get_public(), public);
+
+        // The derivation must never change, or stored hybrid keys would stop matching their public keys.
+        let fixed = HybridSecretKey(Zeroizing::new([1u8; SEED_LEN]));
+        assert_eq!(fixed.get_public().fingerprint().to_string(), "37a4 5089 ba71 1aec fc14 c34c 3206 17e9");
+
+        let ephemeral = EphemeralKey::new().unwrap();
+        let (ciphertext, wrapping_key) = public.derive_wrapping_key(&ephemeral).unwrap();
+        assert_eq!(ciphertext.len(), MLKEM_CIPHERTEXT_LEN);
+        let unwrapped = key.derive_wrapping_key(&ephemeral.get_public(), &ciphertext).unwrap();
+        assert_eq!(unwrapped.as_ref(), wrapping_key.as_ref());
+        assert_ne!(fixed.derive_wrapping_key(&ephemeral.get_public(), &ciphertext).unwrap().as_ref(), wrapping_key.as_ref());
+        assert!(key.derive_wrapping_key(&ephemeral.get_public(), &ciphertext[1..]).is_none());
+
+        // A coefficient of 0xfff is above the ML-KEM modulus.
+        let mut bytes = public.to_bytes();
+        bytes[32..35].copy_from_slice(&[0xff; 3]);
+        let unreduced = bech32::encode(HYBRID_PUBLIC_KEY_HRP, &bytes);
+        assert!(matches!(unreduced.parse::<HybridPublicKey>(), Err(Error::BadKey)));
+        let classical = UserSecretKey::new().unwrap().get_public().to_string();
+        assert!(matches!(classical.parse::<HybridPublicKey>(), Err(Error::WrongKeyKind("hybrid public key", "public key"))));
+    }
+}
@@ -0,0 +1,206 @@
+use crate::encryption::Scheme;
+use crate::header::{self, Header, Stanza};
+use crate::{signature, stream, Error};
+use rustc_hex::{FromHex, ToHex};
+use std::fmt;
+use std::io::{self, Read};
+use std::str::FromStr;
+
+/// Everything that can be learned about an encrypted file without a key.
+#[derive(Debug)]
+pub struct FileInfo {
+    pub version: u8,
+    pub scheme: Scheme,
+    /// The header's ephemeral public key. Nobody holds its private key, so it's kept as raw bytes rather than as a `UserPublicKey`
+    /// that could be mistaken for a recipient.
+    pub ephemeral: [u8; 32],
+    pub recipient_hints: bool,
+    pub signed: bool,
+    pub stanzas: Vec<StanzaInfo>,
+    pub header_len: u64,
+    pub payload_len: u64,
+}
+
+/// A single wrapped file key in the header.
+#[derive(Debug)]
+pub struct StanzaInfo {
+    /// The kind of the wrapped key, for a stanza added by `Decryptor::add_recipients` the kind of the stanza inside it.
+    pub kind: u8,
+    pub len: usize,
+    /// Whether the stanza was added by `Decryptor::add_recipients` after the file was encrypted.
+    pub added: bool,
+    /// The recipient hint of an X25519 or hybrid stanza, if the file was encrypted with hints.
+    pub hint: Option<[u8; header::HINT_LEN]>,
+    /// The scrypt work factor of a passphrase stanza.
+    pub work_factor: Option<u8>,
+    /// How many shares are needed to decrypt, for a share of the file key.
+    pub threshold: Option<u8>,
+}
+
+impl FileInfo {
+    /// The length of the decrypted file, `None` if the payload length doesn't match any encrypted stream.
+    pub fn plaintext_len(&self) -> Option<u64> {
+        let len = stream::plaintext_len(self.payload_len)?;
+        if self.signed {
+            len.checked_sub(signature::TRAILER_LEN as u64)
+        } else {
+            Some(len)
+        }
+    }
+}
+
+impl StanzaInfo {
+    pub fn kind_name(&self) -> &'static str {
+        match self.kind {
+            header::STANZA_X25519 => "X25519",
+            header::STANZA_PASSPHRASE => "passphrase",
+            header::STANZA_X25519_AUTHENTICATED => "X25519 authenticated",
+            header::STANZA_X25519_SHARE => "X25519 share",
+            header::STANZA_X25519_HYBRID => "X25519+ML-KEM-768",
+            _ => "unknown",
+        }
+    }
+}
+
+/// Refers to a stanza of a header, either by its index or by its recipient hint, as listed by `inspect`.
+/// Parsed from a decimal index or from a `0x` prefixed hint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StanzaRef {
+    Index(usize),
+    Hint([u8; header::HINT_LEN]),
+}
+
+impl StanzaRef {
+    pub(crate) fn matches(&self, index: usize, stanza: &Stanza, hints: bool) -> bool {
+        match self {
+            StanzaRef::Index(i) => *i == index,
+            StanzaRef::Hint(hint) => stanza_info(stanza, hints).hint == Some(*hint),
+        }
+    }
+}
+
+impl FromStr for StanzaRef {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || Error::NoSuchStanza(s.to_owned());
+        match s.strip_prefix("0x") {
+            Some(hex) if hex.len() == 2 * header::HINT_LEN => {
+                let bytes: Vec<u8> = hex.from_hex().map_err(|_| bad())?;
+                let mut hint = [0u8; header::HINT_LEN];
+                hint.copy_from_slice(&bytes);
+                Ok(StanzaRef::Hint(hint))
+            }
+            Some(_) => Err(bad()),
+            None => s.parse().map(StanzaRef::Index).map_err(|_| bad()),
+        }
+    }
+}
+
+impl fmt::Display for StanzaRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StanzaRef::Index(index) => write!(f, "{}", index),
+            StanzaRef::Hint(hint) => write!(f, "0x{}", hint.to_hex::<String>()),
+        }
+    }
+}
+
+/// What can be learned about a stanza without a key, looking inside the stanzas added to an existing file.
+pub(crate) fn stanza_info(stanza: &Stanza, recipient_hints: bool) -> StanzaInfo {
+    let (inner, added) = match stanza.unwrap_added() {
+        Some((_, inner)) => (inner, true),
+        None => (stanza.clone(), false),
+    };
+    let mut info = StanzaInfo { kind: inner.kind, len: stanza.body.len(), added, hint: None, work_factor: None, threshold: None };
+    let hint_len = if recipient_hints { header::HINT_LEN } else { 0 };
+    match inner.kind {
+        header::STANZA_X25519 | header::STANZA_X25519_AUTHENTICATED | header::STANZA_X25519_SHARE | header::STANZA_X25519_HYBRID
+            if recipient_hints && inner.body.len() >= header::HINT_LEN =>
+        {
+            let mut hint = [0u8; header::HINT_LEN];
+            hint.copy_from_slice(&inner.body[..header::HINT_LEN]);
+            info.hint = Some(hint);
+        }
+        header::STANZA_PASSPHRASE => info.work_factor = inner.body.first().cloned(),
+        _ => (),
+    }
+    if inner.kind == header::STANZA_X25519_SHARE {
+        info.threshold = inner.body.get(hint_len).cloned();
+    }
+    info
+}
+
+/// Parses the header of an encrypted file and measures its payload, the MAC and the payload aren't checked.
+pub fn inspect<R: Read>(input: &mut R) -> Result<FileInfo, Error> {
+    let header = Header::read(&mut *input)?;
+    let recipient_hints = header.flags & header::FLAG_RECIPIENT_HINTS != 0;
+    let stanzas = header.stanzas.iter().map(|stanza| stanza_info(stanza, recipient_hints)).collect();
+    let payload_len = io::copy(input, &mut io::sink())?;
+
+    Ok(FileInfo {
+        version: header::VERSION,
+        scheme: header.scheme,
+        ephemeral: *header.ephemeral.as_bytes(),
+        recipient_hints,
+        signed: header.flags & header::FLAG_SIGNED != 0,
+        stanzas,
+        header_len: header.encoded_len()? as u64,
+        payload_len,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::generate_random_keys;
+    use crate::{Encryptor, HybridSecretKey};
+
+    #[test]
+    fn inspect_encrypted_file() {
+        let keys = generate_random_keys(2);
+        let data = vec![5u8; stream::CHUNK_SIZE + 10];
+        let mut encrypted = Vec::new();
+        let encryptor =
+            Encryptor::new().recipients(keys.iter().map(|(_, p)| *p)).recipient_hints(true).work_factor(10).passphrase("pass");
+        let encryptor = encryptor.threshold(2, keys.iter().map(|(_, p)| *p)).recipient(HybridSecretKey::new().unwrap().get_public());
+        encryptor.encrypt(&mut &data[..], &mut encrypted).unwrap();
+
+        let info = inspect(&mut &encrypted[..]).unwrap();
+        assert_eq!(info.scheme, Scheme::AES256GCM);
+        assert!(info.recipient_hints && !info.signed);
+        assert_eq!(&info.ephemeral, Header::read(&mut &encrypted[..]).unwrap().ephemeral.as_bytes());
+        assert_eq!(info.header_len + info.payload_len, encrypted.len() as u64);
+        assert_eq!(info.plaintext_len(), Some(data.len() as u64));
+        let kinds: Vec<_> = info.stanzas.iter().map(StanzaInfo::kind_name).collect();
+        assert_eq!(kinds, ["X25519", "X25519", "X25519+ML-KEM-768", "passphrase", "X25519 share", "X25519 share"]);
+        assert!(info.stanzas[0].hint.is_some() && info.stanzas[2].hint.is_some());
+        assert!(info.stanzas[3].hint.is_none() && info.stanzas[5].hint.is_some());
+        assert_eq!(info.stanzas[3].work_factor, Some(10));
+        assert_eq!(info.stanzas[4].threshold, Some(2));
+        assert_eq!(info.stanzas[0].threshold, None);
+
+        // Stanzas added later are reported with the kind and hint of the stanza inside them.
+        let mut added = Vec::new();
+        crate::Decryptor::new(keys[0].0.clone()).add_recipients(&[keys[1].1], &mut &encrypted[..], &mut added).unwrap();
+        let added = inspect(&mut &added[..]).unwrap();
+        let last = added.stanzas.last().unwrap();
+        assert_eq!(last.kind_name(), "X25519");
+        assert!(last.added && last.hint.is_some() && !added.stanzas[0].added);
+
+        let header_len = info.header_len as usize;
+        assert!(matches!(inspect(&mut &encrypted[..header_len - 1]), Err(Error::TruncatedHeader)));
+        assert!(matches!(inspect(&mut &b"not a lorenz file"[..]), Err(Error::BadHeader)));
+    }
+
+    #[test]
+    fn parse_stanza_ref() {
+        assert_eq!("12".parse::<StanzaRef>().unwrap(), StanzaRef::Index(12));
+        let hint: StanzaRef = "0x0102030405060708".parse().unwrap();
+        assert_eq!(hint, StanzaRef::Hint([1, 2, 3, 4, 5, 6, 7, 8]));
+        assert_eq!(hint.to_string(), "0x0102030405060708");
+        for bad in &["", "-1", "0x0102", "0x01020304050607zz", "lorenz1"] {
+            assert!(matches!(bad.parse::<StanzaRef>(), Err(Error::NoSuchStanza(_))));
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use crate::encryption::{self, Scheme};
+use crate::passphrase::{self, DEFAULT_WORK_FACTOR, SALT_LEN};
+use crate::x25519::UserSecretKey;
+use crate::{Error, Secret};
+use std::io::{Read, Write};
+use zeroize::Zeroizing;
+
+// A key file holds a private key encrypted under a passphrase.
+// Layout: `MAGIC | version | scrypt work factor | salt | encrypted private key`.
+const MAGIC: &[u8; 9] = b"LORENZKEY";
+const VERSION: u8 = 1;
+const LABEL: &[u8] = b"lorenz-key-file";
+const SCHEME: Scheme = Scheme::Chacha20Poly1305;
+
+impl UserSecretKey {
+    /// Writes the key into `output` encrypted with a key derived from `passphrase`.
+    pub fn write_key_file<W: Write>(&self, passphrase: &str, output: &mut W) -> Result<(), Error> {
+        self.write_key_file_with_work_factor(passphrase, DEFAULT_WORK_FACTOR, output)
+    }
+
+    /// Like `write_key_file` but with an explicit scrypt work factor (log2 of N).
+    pub fn write_key_file_with_work_factor<W: Write>(&self, passphrase: &str, work_factor: u8, output: &mut W) -> Result<(), Error> {
+        let salt = passphrase::generate_salt()?;
+        let key = passphrase::derive_key(passphrase.as_bytes(), LABEL, &salt, work_factor)?;
+        let encrypted = encryption::encrypt_data(key.as_ref(), self.to_bytes().to_vec(), SCHEME)?;
+
+        output.write_all(MAGIC)?;
+        output.write_all(&[VERSION, work_factor])?;
+        output.write_all(&salt)?;
+        output.write_all(&encrypted)?;
+        Ok(())
+    }
+
+    /// Reads a key written by `write_key_file`, fails with `Error::BadPassphrase` if the passphrase is wrong.
+    pub fn read_key_file<R: Read>(input: &mut R, passphrase: &str) -> Result<Self, Error> {
+        let mut magic = [0u8; 9];
+        input.read_exact(&mut magic).map_err(Error::bad_key_file)?;
+        if &magic != MAGIC {
+            return Err(Error::BadKeyFile);
+        }
+        let mut params = [0u8; 2];
+        input.read_exact(&mut params).map_err(Error::bad_key_file)?;
+        let [version, work_factor] = params;
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let mut salt = [0u8; SALT_LEN];
+        input.read_exact(&mut salt).map_err(Error::bad_key_file)?;
+        let mut encrypted = vec![0u8; SCHEME.get_encrypted_key_size()];
+        input.read_exact(&mut encrypted).map_err(Error::bad_key_file)?;
+
+        let key = passphrase::derive_key(passphrase.as_bytes(), LABEL, &salt, work_factor)?;
+        let decrypted = encryption::decrypt_data(key.as_ref(), encrypted, SCHEME).map_err(Error::bad_passphrase)?;
+        let decrypted = Secret::from_vec(decrypted);
+        let mut bytes = Zeroizing::new([0u8; 32]);
+        bytes.copy_from_slice(decrypted.as_ref());
+        Ok(UserSecretKey::from_bytes(&bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_file_roundtrip() {
+        let key = UserSecretKey::new().unwrap();
+        let mut file = Vec::new();
+        key.write_key_file_with_work_factor("correct horse", 10, &mut file).unwrap();
+
+        let read = UserSecretKey::read_key_file(&mut &file[..], "correct horse").unwrap();
+        assert_eq!(read.get_public(), key.get_public());
+
+        assert!(matches!(UserSecretKey::read_key_file(&mut &file[..], "battery staple"), Err(Error::BadPassphrase)));
+        assert!(matches!(UserSecretKey::read_key_file(&mut &file[..file.len() - 1], "correct horse"), Err(Error::BadKeyFile)));
+    }
+}
@@ -0,0 +1,356 @@
+use crate::bech32::{self, SECRET_KEY_HRP};
+use crate::fingerprint;
+use crate::x25519::{UserPublicKey, UserSecretKey};
+use crate::Error;
+use std::collections::HashSet;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+const CONTACTS: &str = "contacts";
+const IDENTITIES: &str = "identities";
+const GROUPS: &str = "groups";
+
+/// A directory of named keys: contacts are public keys of other people,
+/// identities are our own private keys and are tried when decrypting.
+/// Groups are named lists of keys, referred to as `@name`.
+///
+/// Layout: `contacts/<name>` and `identities/<name>`, each holding a single bech32 encoded key (hex in keyrings written by older versions),
+/// and `groups/<name>` holding a member per line, either a key name or a public key.
+pub struct Keyring {
+    dir: PathBuf,
+}
+
+/// Whether a named key in the keyring is someone else's public key or one of our private keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyKind {
+    Contact,
+    Identity,
+}
+
+impl Keyring {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Keyring { dir: dir.into() }
+    }
+
+    /// `$LORENZ_HOME` if it's set, otherwise `lorenz` in the user's config directory (`$XDG_CONFIG_HOME` or `~/.config`).
+    pub fn default_dir() -> Option<PathBuf> {
+        if let Some(dir) = env::var_os("LORENZ_HOME") {
+            return Some(dir.into());
+        }
+        let config = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+        Some(config.join("lorenz"))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, kind: KeyKind, name: &str) -> PathBuf {
+        let sub = match kind {
+            KeyKind::Contact => CONTACTS,
+            KeyKind::Identity => IDENTITIES,
+        };
+        self.dir.join(sub).join(name)
+    }
+
+    /// Finds which kind of key `name` is, names are unique across contacts and identities.
+    pub fn kind_of(&self, name: &str) -> Result<KeyKind, Error> {
+        check_name(name)?;
+        [KeyKind::Contact, KeyKind::Identity]
+            .iter()
+            .cloned()
+            .find(|&kind| self.path(kind, name).is_file())
+            .ok_or_else(|| Error::UnknownKeyName(name.to_owned()))
+    }
+
+    pub fn add_contact(&self, name: &str, key: &UserPublicKey) -> Result<(), Error> {
+        self.write_new(KeyKind::Contact, name, key.to_string().as_bytes())
+    }
+
+    pub fn add_identity(&self, name: &str, key: &UserSecretKey) -> Result<(), Error> {
+        self.write_new(KeyKind::Identity, name, bech32::encode(SECRET_KEY_HRP, &*key.to_bytes()).as_bytes())
+    }
+
+    fn write_new(&self, kind: KeyKind, name: &str, key: &[u8]) -> Result<(), Error> {
+        check_name(name)?;
+        if self.kind_of(name).is_ok() {
+            return Err(Error::KeyNameTaken(name.to_owned()));
+        }
+        let path = self.path(kind, name);
+        let dir = path.parent().expect("always in a sub directory");
+        fs::create_dir_all(dir)?;
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+            if kind == KeyKind::Identity {
+                fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+                options.mode(0o600);
+            }
+        }
+        let mut file = options.open(path)?;
+        file.write_all(key)?;
+        file.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn read(&self, kind: KeyKind, name: &str) -> Result<Zeroizing<String>, Error> {
+        let contents = Zeroizing::new(fs::read_to_string(self.path(kind, name))?);
+        Ok(Zeroizing::new(contents.trim().to_owned()))
+    }
+
+    /// The public key of a contact, or of one of our identities.
+    pub fn public_key(&self, name: &str) -> Result<UserPublicKey, Error> {
+        match self.kind_of(name)? {
+            KeyKind::Contact => {
+                let key = self.read(KeyKind::Contact, name)?;
+                match key.parse() {
+                    Err(Error::UntypedHexKey) => UserPublicKey::from_hex(&key),
+                    res => res,
+                }
+            }
+            KeyKind::Identity => Ok(self.identity(name)?.get_public()),
+        }
+    }
+
+    pub fn identity(&self, name: &str) -> Result<UserSecretKey, Error> {
+        check_name(name)?;
+        let key = self.read(KeyKind::Identity, name)?;
+        match key.parse() {
+            Err(Error::UntypedHexKey) => UserSecretKey::from_hex(&key),
+            res => res,
+        }
+    }
+
+    /// The name and key of the contact or identity whose fingerprint is, or starts with, `id`.
+    pub fn find_fingerprint(&self, id: &str) -> Result<(String, UserPublicKey), Error> {
+        let mut found = None;
+        for kind in &[KeyKind::Contact, KeyKind::Identity] {
+            for name in self.names(*kind)? {
+                let key = self.public_key(&name)?;
+                if key.fingerprint().matches(id) {
+                    if found.is_some() {
+                        return Err(Error::AmbiguousFingerprint(id.to_owned()));
+                    }
+                    found = Some((name, key));
+                }
+            }
+        }
+        found.ok_or_else(|| Error::UnknownFingerprint(id.to_owned()))
+    }
+
+    pub fn remove(&self, name: &str) -> Result<(), Error> {
+        let kind = self.kind_of(name)?;
+        Ok(fs::remove_file(self.path(kind, name))?)
+    }
+
+    /// The public keys `name` refers to: the members of a group for `@group`,
+    /// otherwise a single contact, identity, fingerprint of one of them, or public key.
+    pub fn resolve(&self, name: &str) -> Result<Vec<UserPublicKey>, Error> {
+        match name.strip_prefix('@') {
+            Some(group) => self.group(group)?.iter().map(|member| self.resolve_group_member(member)).collect(),
+            None => self.resolve_member(name).map(|key| vec![key]),
+        }
+    }
+
+    /// Groups written by older versions can list public keys in hex, a group only ever lists public keys.
+    /// Names and fingerprints can be all hex digits too, so a member is only read as a hex key when it's a whole key
+    /// that isn't a name or fingerprint in the keyring.
+    fn resolve_group_member(&self, member: &str) -> Result<UserPublicKey, Error> {
+        match self.resolve_member(member) {
+            Err(Error::UnknownKeyName(_)) | Err(Error::UnknownFingerprint(_)) if is_hex_key(member) => UserPublicKey::from_hex(member),
+            res => res,
+        }
+    }
+
+    fn resolve_member(&self, member: &str) -> Result<UserPublicKey, Error> {
+        match self.public_key(member) {
+            Err(Error::UnknownKeyName(_)) | Err(Error::BadKeyName(_)) if fingerprint::is_fingerprint(member) => {
+                self.find_fingerprint(member).map(|(_, key)| key)
+            }
+            Err(Error::UnknownKeyName(_)) | Err(Error::BadKeyName(_)) => {
+                member.parse().map_err(|_| Error::UnknownKeyName(member.to_owned()))
+            }
+            res => res,
+        }
+    }
+
+    /// Creates or replaces a group, every member must be a key name in the keyring or a public key.
+    /// Members with the same public key as an earlier member are left out.
+    pub fn set_group(&self, name: &str, members: &[String]) -> Result<(), Error> {
+        check_name(name)?;
+        let mut seen = HashSet::new();
+        let mut contents = String::new();
+        for member in members {
+            if seen.insert(self.resolve_group_member(member)?) {
+                contents.push_str(member);
+                contents.push('\n');
+            }
+        }
+        let path = self.dir.join(GROUPS).join(name);
+        fs::create_dir_all(path.parent().expect("always in a sub directory"))?;
+        Ok(fs::write(path, contents)?)
+    }
+
+    /// The members of a group, as they were given to `set_group`.
+    pub fn group(&self, name: &str) -> Result<Vec<String>, Error> {
+        check_name(name)?;
+        match fs::read_to_string(self.dir.join(GROUPS).join(name)) {
+            Ok(contents) => Ok(contents.lines().map(str::trim).filter(|line| !line.is_empty()).map(str::to_owned).collect()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Err(Error::UnknownGroup(name.to_owned())),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn remove_group(&self, name: &str) -> Result<(), Error> {
+        self.group(name)?;
+        Ok(fs::remove_file(self.dir.join(GROUPS).join(name))?)
+    }
+
+    pub fn group_names(&self) -> Result<Vec<String>, Error> {
+        list_dir(&self.dir.join(GROUPS))
+    }
+
+    /// The names of all the keys of `kind`, sorted.
+    pub fn names(&self, kind: KeyKind) -> Result<Vec<String>, Error> {
+        list_dir(&self.path(kind, ""))
+    }
+
+    /// All of our private keys, to try them all when decrypting.
+    pub fn identities(&self) -> Result<Vec<UserSecretKey>, Error> {
+        self.names(KeyKind::Identity)?.iter().map(|name| self.identity(name)).collect()
+    }
+}
+
+/// The valid names in `dir`, sorted.
+fn list_dir(dir: &Path) -> Result<Vec<String>, Error> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut names = Vec::new();
+    for entry in entries {
+        if let Some(name) = entry?.file_name().to_str() {
+            if check_name(name).is_ok() {
+                names.push(name.to_owned());
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Names are used as file names, so only a conservative set of characters is allowed.
+fn check_name(name: &str) -> Result<(), Error> {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.';
+    if name.is_empty() || name.starts_with('.') || !name.chars().all(valid_char) {
+        return Err(Error::BadKeyName(name.to_owned()));
+    }
+    Ok(())
+}
+
+/// Whether a group member is a whole public key in the older hex form.
+fn is_hex_key(member: &str) -> bool {
+    let hex = member.strip_prefix("0x").unwrap_or(member);
+    hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::generate_random_keys;
+    use rustc_hex::ToHex;
+    use tempfile::tempdir;
+
+    #[test]
+    fn keyring_contacts_and_identities() {
+        let dir = tempdir().unwrap();
+        let keyring = Keyring::new(dir.path());
+        let keys = generate_random_keys(3);
+        assert!(keyring.identities().unwrap().is_empty());
+
+        keyring.add_contact("alice", &keys[0].1).unwrap();
+        keyring.add_contact("bob.work", &keys[1].1).unwrap();
+        keyring.add_identity("me", &keys[2].0).unwrap();
+        assert!(matches!(keyring.add_contact("me", &keys[0].1), Err(Error::KeyNameTaken(_))));
+        assert!(matches!(keyring.add_contact("../me", &keys[0].1), Err(Error::BadKeyName(_))));
+
+        assert_eq!(keyring.names(KeyKind::Contact).unwrap(), ["alice", "bob.work"]);
+        assert_eq!(keyring.kind_of("me").unwrap(), KeyKind::Identity);
+        assert_eq!(keyring.public_key("alice").unwrap(), keys[0].1);
+        assert_eq!(keyring.public_key("me").unwrap(), keys[2].1);
+        let identities = keyring.identities().unwrap();
+        assert_eq!(identities.len(), 1);
+        assert_eq!(identities[0].get_public(), keys[2].1);
+
+        let bob = keys[1].1.to_string();
+        let members = ["alice", "me", "bob.work", &bob].iter().map(|&m| m.to_owned()).collect::<Vec<_>>();
+        keyring.set_group("team", &members).unwrap();
+        assert_eq!(keyring.group("team").unwrap(), ["alice", "me", "bob.work"]);
+        assert_eq!(keyring.resolve("@team").unwrap(), [keys[0].1, keys[2].1, keys[1].1]);
+        assert_eq!(keyring.resolve(&bob).unwrap(), [keys[1].1]);
+        let fingerprint = keys[1].1.fingerprint();
+        assert_eq!(keyring.resolve(&fingerprint.to_string()).unwrap(), [keys[1].1]);
+        assert_eq!(keyring.resolve(&fingerprint.short_id()).unwrap(), [keys[1].1]);
+        assert_eq!(keyring.find_fingerprint(&fingerprint.short_id()).unwrap().0, "bob.work");
+        assert!(matches!(keyring.resolve("0000000000000000"), Err(Error::UnknownFingerprint(_))));
+        assert_eq!(keyring.group_names().unwrap(), ["team"]);
+        assert!(matches!(keyring.set_group("typo", &["alcie".to_owned()]), Err(Error::UnknownKeyName(_))));
+        assert!(matches!(keyring.resolve("@typo"), Err(Error::UnknownGroup(_))));
+        keyring.remove_group("team").unwrap();
+        assert!(matches!(keyring.resolve("@team"), Err(Error::UnknownGroup(_))));
+
+        keyring.remove("alice").unwrap();
+        assert!(matches!(keyring.public_key("alice"), Err(Error::UnknownKeyName(_))));
+        assert!(matches!(keyring.remove("alice"), Err(Error::UnknownKeyName(_))));
+    }
+
+    #[test]
+    fn keyring_written_with_hex_keys() {
+        let dir = tempdir().unwrap();
+        let keyring = Keyring::new(dir.path());
+        let keys = generate_random_keys(2);
+        let hex = |bytes: &[u8]| format!("0x{}\n", bytes.to_hex::<String>());
+        for sub in &[CONTACTS, IDENTITIES, GROUPS] {
+            fs::create_dir_all(dir.path().join(sub)).unwrap();
+        }
+        fs::write(dir.path().join(CONTACTS).join("alice"), hex(keys[0].1.as_bytes())).unwrap();
+        fs::write(dir.path().join(IDENTITIES).join("me"), hex(&keys[1].0.to_bytes()[..])).unwrap();
+        fs::write(dir.path().join(GROUPS).join("team"), format!("me\n{}", hex(keys[0].1.as_bytes()))).unwrap();
+
+        // The directory a key is in tells what kind of key it is.
+        assert_eq!(keyring.public_key("alice").unwrap(), keys[0].1);
+        assert_eq!(keyring.identity("me").unwrap().get_public(), keys[1].1);
+        assert_eq!(keyring.resolve("@team").unwrap(), [keys[1].1, keys[0].1]);
+        // A hex key typed out isn't accepted as a recipient.
+        assert!(keyring.resolve(hex(keys[0].1.as_bytes()).trim()).is_err());
+    }
+
+    #[test]
+    fn group_members_made_of_hex_digits() {
+        let dir = tempdir().unwrap();
+        let keyring = Keyring::new(dir.path());
+        let keys = generate_random_keys(3);
+        keyring.add_contact("cafe", &keys[0].1).unwrap();
+        keyring.add_contact("bob", &keys[1].1).unwrap();
+        let short_id = keys[1].1.fingerprint().short_id();
+        let hex = keys[2].1.as_bytes().to_hex::<String>();
+
+        // Names and fingerprints win over hex keys, only a whole key that's neither is read as one.
+        let members = ["cafe".to_owned(), short_id.clone()];
+        keyring.set_group("team", &members).unwrap();
+        assert_eq!(keyring.group("team").unwrap(), ["cafe", short_id.as_str()]);
+        assert_eq!(keyring.resolve("@team").unwrap(), [keys[0].1, keys[1].1]);
+        fs::write(dir.path().join(GROUPS).join("old"), format!("cafe\n{}\n", hex)).unwrap();
+        assert_eq!(keyring.resolve("@old").unwrap(), [keys[0].1, keys[2].1]);
+        assert!(matches!(keyring.set_group("typo", &["dead".to_owned()]), Err(Error::UnknownKeyName(_))));
+    }
+}
@@ -0,0 +1,136 @@
+//! Lorenz encrypts files for multiple participants,
+//! any one of the recipients can decrypt the file with their own private key.
+//!
+//! [`Encryptor`] and [`Decryptor`] work over any `Read`/`Write`,
+//! either all at once or as a wrapping `StreamWriter`/`StreamReader`.
+
+mod bech32;
+mod encryption;
+mod error;
+mod fingerprint;
+mod header;
+mod hybrid;
+mod inspect;
+mod keyfile;
+mod keyring;
+mod logic;
+mod mnemonic;
+mod passphrase;
+mod recipient;
+mod secret;
+mod shamir;
+mod sharefile;
+mod signature;
+mod ssh;
+mod stream;
+mod x25519;
+
+pub use encryption::Scheme;
+pub use error::Error;
+pub use fingerprint::Fingerprint;
+pub use header::{Stanza, HINT_LEN};
+pub use hybrid::{HybridPublicKey, HybridSecretKey};
+pub use inspect::{inspect, FileInfo, StanzaInfo, StanzaRef};
+pub use keyring::{KeyKind, Keyring};
+pub use logic::{decrypt_file_with_keys, encrypt_file_with_keys, Decryptor, Encryptor};
+pub use passphrase::DEFAULT_WORK_FACTOR;
+pub use recipient::{recipient_hint, Identity, Recipient, UnwrapContext, WrapContext};
+pub use secret::Secret;
+pub use signature::{UserSigningKey, UserVerifyingKey};
+pub use stream::{StreamReader, StreamWriter};
+pub use x25519::{UserPublicKey, UserSecretKey};
+
+#[cfg(test)]
+mod tests {
+    use rand::{thread_rng, RngCore};
+    use std::fs::File;
+    use std::io::{self, Read, Seek, SeekFrom, Write};
+
+    use super::*;
+    use crate::header::{self, Header, Stanza};
+    use tempfile::tempfile;
+
+    #[test]
+    fn full_test() {
+        let mut input = get_rand_file();
+        let mut encrypted = tempfile().unwrap();
+        let mut decrypted = tempfile().unwrap();
+
+        let mut keys = encrypt(6, &mut input, &mut encrypted);
+
+        decrypt(keys.remove(4), &mut encrypted, &mut decrypted);
+
+        let mut before = Vec::new();
+        let mut after = Vec::new();
+
+        input.read_to_end(&mut before).unwrap();
+        decrypted.read_to_end(&mut after).unwrap();
+        assert_eq!(before, after);
+    }
+
+    pub fn get_rand_file() -> File {
+        let mut input = vec![0u8; 1986];
+        let mut rng = thread_rng();
+        rng.fill_bytes(&mut input);
+        let mut f = tempfile().unwrap();
+        f.write_all(&input).unwrap();
+        f.seek(SeekFrom::Start(0)).unwrap();
+        f
+    }
+
+    fn decrypt(key: UserSecretKey, mut encrypted: &mut File, output: &mut File) {
+        let header = Header::read(&mut encrypted).unwrap();
+
+        let shared = key.derive_secret(&header.ephemeral, 32);
+        let aes_key = find_aes_key(&header.stanzas, shared).unwrap();
+        header.verify(&aes_key).unwrap();
+
+        let mut reader = StreamReader::new(&aes_key, Scheme::AES256GCM, &mut encrypted).unwrap();
+        io::copy(&mut reader, output).unwrap();
+
+        encrypted.seek(SeekFrom::Start(0)).unwrap();
+        output.seek(SeekFrom::Start(0)).unwrap();
+    }
+
+    fn find_aes_key(stanzas: &[Stanza], shared: Secret) -> Option<Secret> {
+        for stanza in stanzas {
+            if let Ok(key) = encryption::decrypt_data(shared.as_ref(), stanza.body.clone(), Scheme::AES256GCM) {
+                return Some(Secret::from_vec(key));
+            }
+        }
+        None
+    }
+
+    fn encrypt(keys: usize, input_file: &mut File, output: &mut File) -> Vec<UserSecretKey> {
+        let aes = Secret::generate32().unwrap();
+        let ephemeral = x25519::EphemeralKey::new().unwrap();
+        let mut header = Header::new(Scheme::AES256GCM, ephemeral.get_public());
+
+        let keys = generate_random_keys(keys);
+        for (_, public) in &keys {
+            let shared = ephemeral.derive_secret(&public.0, 32);
+            let enc_key = encryption::encrypt_data(shared.as_ref(), aes.clone().into_vec(), Scheme::AES256GCM).unwrap();
+            header.stanzas.push(Stanza::new(header::STANZA_X25519, enc_key));
+        }
+        header.seal(&aes).unwrap();
+        header.write(output).unwrap();
+
+        let mut writer = StreamWriter::new(&aes, Scheme::AES256GCM, &mut *output).unwrap();
+        io::copy(input_file, &mut writer).unwrap();
+        writer.finish().unwrap();
+        output.seek(SeekFrom::Start(0)).unwrap();
+        input_file.seek(SeekFrom::Start(0)).unwrap();
+
+        keys.into_iter().map(|(key, _)| key).collect()
+    }
+
+    pub fn generate_random_keys(amount: usize) -> Vec<(UserSecretKey, UserPublicKey)> {
+        let mut res = Vec::with_capacity(amount);
+        for _ in 0..amount {
+            let key = UserSecretKey::new().unwrap();
+            let public = key.get_public();
+            res.push((key, public));
+        }
+        res
+    }
+}
@@ -1,74 +1,535 @@
 use crate::x25519::*;
 use crate::*;
 use encryption::Scheme;
-use std::fs::*;
-use std::io::{self, Read, Seek, SeekFrom, Write};
-use x25519_dalek::PublicKey;
+use header::{Header, Stanza, HINT_LEN};
+use inspect::StanzaRef;
+use passphrase::Passphrase;
+use recipient::{recipient_hint, UnwrapContext, WrapContext};
+use shamir::Share;
+use signature::{Signer, Verifier};
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use stream::{StreamReader, StreamWriter};
+use zeroize::Zeroizing;
 
-fn file_len(file: &File) -> usize {
-    file.metadata().map(|m| m.len() as usize + 1).unwrap_or(0)
+/// Encrypts data for a set of recipients, any one of them can decrypt it.
+///
+/// ```no_run
+/// # fn main() -> Result<(), lorenz::Error> {
+/// use lorenz::{Encryptor, Scheme, UserSecretKey};
+///
+/// let recipient = UserSecretKey::new()?.get_public();
+/// let mut output = Vec::new();
+/// Encryptor::new().recipient(recipient).scheme(Scheme::Chacha20Poly1305).encrypt(&mut &b"secret"[..], &mut output)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Encryptor {
+    recipients: Vec<Arc<dyn Recipient + Send + Sync>>,
+    passphrases: Vec<Secret>,
+    work_factor: u8,
+    scheme: Scheme,
+    hints: bool,
+    signer: Option<UserSigningKey>,
+    sender: Option<Arc<UserSecretKey>>,
+    threshold: Option<(u8, Vec<UserPublicKey>)>,
 }
 
-pub fn encrypt_file_with_keys(input_file: &mut File, keys: Vec<PublicKey>, output: &mut File, scheme: Scheme) -> Result<(), Error> {
-    let mut input = Vec::with_capacity(file_len(&input_file));
-    input_file.read_to_end(&mut input)?;
-    let aes = Secret::generate32()?;
-    let ephemeral = x25519::EphemeralKey::new()?;
-    output.write_all(ephemeral.get_public().as_bytes())?;
-    output.write_all(&[keys.len() as u8])?;
+impl Encryptor {
+    pub fn new() -> Self {
+        Encryptor {
+            recipients: Vec::new(),
+            passphrases: Vec::new(),
+            work_factor: passphrase::DEFAULT_WORK_FACTOR,
+            scheme: Scheme::AES256GCM,
+            hints: false,
+            signer: None,
+            sender: None,
+            threshold: None,
+        }
+    }
+
+    /// Adds a recipient, any kind of key implementing `Recipient` can be mixed with the others:
+    /// a `UserPublicKey`, or a `HybridPublicKey` whose wrapped key stays safe even if X25519 gets broken.
+    pub fn recipient<K: Recipient + Send + Sync + 'static>(mut self, key: K) -> Self {
+        self.recipients.push(Arc::new(key));
+        self
+    }
+
+    pub fn recipients<I, K>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Recipient + Send + Sync + 'static,
+    {
+        self.recipients.extend(keys.into_iter().map(|key| Arc::new(key) as Arc<dyn Recipient + Send + Sync>));
+        self
+    }
+
+    /// Adds a recipient that can decrypt the file with `passphrase` instead of a private key.
+    /// A file can only have a single passphrase, encrypting with more fails with `Error::MalformedHeader`.
+    pub fn passphrase(mut self, passphrase: &str) -> Self {
+        self.passphrases.push(Secret::from_vec(passphrase.as_bytes().to_vec()));
+        self
+    }
+
+    /// The scrypt work factor (log2 of N) used for passphrase recipients.
+    pub fn work_factor(mut self, work_factor: u8) -> Self {
+        self.work_factor = work_factor;
+        self
+    }
+
+    pub fn scheme(mut self, scheme: Scheme) -> Self {
+        self.scheme = scheme;
+        self
+    }
+
+    /// Adds a short tag to every public key recipient, so recipients can find their wrapped key directly.
+    /// The tags are derived from the shared secrets, so only the recipients themselves can match them.
+    /// Without hints (the default) every wrapped key has to be tried.
+    pub fn recipient_hints(mut self, hints: bool) -> Self {
+        self.hints = hints;
+        self
+    }
+
+    /// Signs the file with `key`, the signature is encrypted along with the data so only the recipients can see who signed it.
+    pub fn sign_with(mut self, key: UserSigningKey) -> Self {
+        self.signer = Some(key);
+        self
+    }
+
+    /// Wraps the file key for the X25519 recipient with `key` taking part in the key agreement,
+    /// so a recipient that decrypts with `Decryptor::expect_sender` knows the file came from the holder of `key`.
+    /// Unlike a signature this only convinces the recipient itself, which could have made such a file for itself.
+    /// The file must have a single recipient, otherwise encrypting fails with `Error::SenderWithManyRecipients`:
+    /// any other recipient knows the file key too, and could put its own payload behind the authenticated header.
+    pub fn authenticate_as(mut self, key: UserSecretKey) -> Self {
+        self.sender = Some(Arc::new(key));
+        self
+    }
+
+    /// Splits the file key into Shamir shares, one for each of `holders`, so any `threshold` of them can decrypt the file together
+    /// but fewer learn nothing about it. The other recipients can still decrypt the file on their own.
+    pub fn threshold<I: IntoIterator<Item = UserPublicKey>>(mut self, threshold: u8, holders: I) -> Self {
+        self.threshold = Some((threshold, holders.into_iter().collect()));
+        self
+    }
+
+    /// Encrypts everything in `input` into `output`.
+    pub fn encrypt<R: Read, W: Write>(&self, input: &mut R, output: &mut W) -> Result<(), Error> {
+        let mut writer = self.wrap_output(output)?;
+        io::copy(input, &mut writer)?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Writes the header into `output` and returns a writer that encrypts everything written to it.
+    /// `StreamWriter::finish` must be called once all the data was written.
+    pub fn wrap_output<W: Write>(&self, mut output: W) -> Result<StreamWriter<W>, Error> {
+        // A key given more than once (e.g. directly and through a group) gets a single stanza.
+        let mut seen = HashSet::new();
+        let recipients: Vec<_> =
+            self.recipients.iter().filter(|key| key.fingerprint().is_none_or(|fingerprint| seen.insert(fingerprint))).collect();
+        let passphrases: Vec<_> = self.passphrases.iter().map(|p| Passphrase::new(p.clone(), self.work_factor)).collect();
+        let mut seen = HashSet::new();
+        let holders: Vec<_> = self.threshold.iter().flat_map(|(_, holders)| holders).filter(|key| seen.insert(**key)).collect();
+        let count = recipients.len() + passphrases.len() + holders.len();
+        if count == 0 {
+            return Err(Error::NoRecipients);
+        }
+        if count > header::MAX_RECIPIENTS {
+            return Err(Error::TooManyRecipients(count));
+        }
+        if passphrases.len() > 1 {
+            return Err(Error::MalformedHeader("more than one passphrase stanza"));
+        }
+        if self.sender.is_some() && count > 1 {
+            return Err(Error::SenderWithManyRecipients(count));
+        }
+        let scheme = self.scheme;
+        let file_key = Secret::generate32()?;
+        let ephemeral = x25519::EphemeralKey::new()?;
+        let mut header = Header::new(scheme, ephemeral.get_public());
+        if self.hints {
+            header.flags |= header::FLAG_RECIPIENT_HINTS;
+        }
+        if self.signer.is_some() {
+            header.flags |= header::FLAG_SIGNED;
+        }
+
+        let ctx = WrapContext { ephemeral: &ephemeral, sender: self.sender.as_deref(), scheme, hints: self.hints };
+        for recipient in recipients {
+            header.stanzas.push(recipient.wrap_file_key(&file_key, &ctx)?);
+        }
+        for passphrase in &passphrases {
+            header.stanzas.push(passphrase.wrap_file_key(&file_key, &ctx)?);
+        }
+        if let Some((threshold, _)) = self.threshold {
+            let amount = u8::try_from(holders.len()).map_err(|_| Error::BadThreshold(threshold, holders.len()))?;
+            let shares = shamir::split(file_key.as_ref(), threshold, amount)?;
+            for (holder, share) in holders.into_iter().zip(shares) {
+                header.stanzas.push(wrap_share(&share, &ephemeral.derive_secret(&holder.0, 32), self.hints, scheme)?);
+            }
+        }
+        header.seal(&file_key)?;
+        header.write(&mut output)?;
+
+        let writer = StreamWriter::new(&file_key, scheme, output)?;
+        Ok(match &self.signer {
+            Some(key) => writer.with_signer(Signer::new(key.clone(), &header.fixed_bytes())),
+            None => writer,
+        })
+    }
+}
 
-    for key in &keys {
-        let shared = ephemeral.derive_secret(&key, 32);
-        let enc_key = encryption::encrypt_data(shared.as_ref(), aes.clone().into_vec(), scheme)?;
-        output.write_all(&enc_key)?;
+impl Default for Encryptor {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    let enc_file = encryption::encrypt_data(aes.as_ref(), input, scheme)?;
-    output.write_all(&enc_file)?;
-    Ok(())
+/// Decrypts data that was encrypted to the public key of one of its keys or identities, or to one of its passphrases.
+pub struct Decryptor {
+    keys: Vec<UserSecretKey>,
+    identities: Vec<Box<dyn Identity + Send + Sync>>,
+    passphrases: Vec<Secret>,
+    max_work_factor: u8,
+    expected_signer: Option<UserVerifyingKey>,
+    expected_sender: Option<UserPublicKey>,
+    /// Shares from share files, with the ID of the file they belong to.
+    shares: Vec<([u8; sharefile::FILE_ID_LEN], Share)>,
 }
 
-pub fn decrypt_file_with_keys(input_file: &mut File, key: UserSecretKey, output: &mut File, scheme: Scheme) -> Result<(), Error> {
-    let mut pubkey = [0u8; 32];
-    input_file.read_exact(&mut pubkey)?;
-    let amount = take(input_file)?;
+impl Decryptor {
+    pub fn new(key: UserSecretKey) -> Self {
+        Self::empty().key(key)
+    }
+
+    pub fn with_passphrase(passphrase: &str) -> Self {
+        Self::empty().passphrase(passphrase)
+    }
+
+    pub fn with_identity<I: Identity + Send + Sync + 'static>(identity: I) -> Self {
+        Self::empty().identity(identity)
+    }
+
+    fn empty() -> Self {
+        Decryptor {
+            keys: Vec::new(),
+            identities: Vec::new(),
+            passphrases: Vec::new(),
+            max_work_factor: passphrase::DEFAULT_WORK_FACTOR,
+            expected_signer: None,
+            expected_sender: None,
+            shares: Vec::new(),
+        }
+    }
+
+    /// Also try to decrypt with `key`.
+    pub fn key(mut self, key: UserSecretKey) -> Self {
+        self.keys.push(key);
+        self
+    }
+
+    /// Also try to decrypt with `identity`, any kind of key implementing `Identity` like a `HybridSecretKey`.
+    /// X25519 keys belong in `key`, which also unwraps their shares of files encrypted with `Encryptor::threshold`.
+    pub fn identity<I: Identity + Send + Sync + 'static>(mut self, identity: I) -> Self {
+        self.identities.push(Box::new(identity));
+        self
+    }
+
+    /// Also try to decrypt with `passphrase`.
+    pub fn passphrase(mut self, passphrase: &str) -> Self {
+        self.passphrases.push(Secret::from_vec(passphrase.as_bytes().to_vec()));
+        self
+    }
 
-    let shared = key.derive_secret(&pubkey.into(), 32);
-    let (key, left) = find_encrypted_key(input_file, shared, amount, scheme);
-    let key = key.ok_or(Error::BadKey)?;
-    input_file.seek(SeekFrom::Current(i64::from(left) * scheme.get_encrypted_key_size() as i64))?;
+    /// The highest scrypt work factor (log2 of N) a passphrase is tried with, `DEFAULT_WORK_FACTOR` unless raised.
+    /// Files asking for more fail with `Error::BadWorkFactor` instead of spending their time and memory.
+    pub fn max_work_factor(mut self, work_factor: u8) -> Self {
+        self.max_work_factor = work_factor;
+        self
+    }
 
-    let data_size = file_len(input_file) - 32 - 1 - amount as usize * scheme.get_encrypted_key_size();
-    let mut data = Vec::with_capacity(data_size);
+    /// Only accept files signed by `key`, decrypting anything else fails with `Error::BadSignature`.
+    /// The signature is checked once all the data was decrypted, so the plaintext written or read before that is unverified,
+    /// see `decrypt` and `wrap_input`.
+    pub fn expect_signer(mut self, key: UserVerifyingKey) -> Self {
+        self.expected_signer = Some(key);
+        self
+    }
 
-    input_file.read_to_end(&mut data)?;
+    /// Only accept files encrypted with `Encryptor::authenticate_as` by the holder of `sender`'s private key,
+    /// anything else fails with `Error::BadSender`. Only X25519 keys can authenticate a sender, identities and passphrases aren't tried.
+    /// A file with more than one stanza is refused too, since every other recipient could have forged its payload.
+    pub fn expect_sender(mut self, sender: UserPublicKey) -> Self {
+        self.expected_sender = Some(sender);
+        self
+    }
 
-    let original = encryption::decrypt_data(key.as_ref(), data, scheme)?;
-    output.write_all(&original)?;
+    /// Adds the shares from a share file written by `unwrap_shares`, the share file is decrypted with the keys of this decryptor.
+    /// They're combined with any other shares when decrypting the file they belong to.
+    pub fn share_file<R: Read>(mut self, input: &mut R) -> Result<Self, Error> {
+        let mut content = Zeroizing::new(Vec::new());
+        self.decrypt(input, &mut *content)?;
+        let (file_id, shares) = sharefile::decode(&content)?;
+        self.shares.extend(shares.into_iter().map(|share| (file_id, share)));
+        Ok(self)
+    }
 
-    Ok(())
+    /// Unwraps the shares of a threshold file's key held by the keys of this decryptor,
+    /// and writes them into `output` as a share file encrypted to `combiner`. Returns the amount of shares.
+    /// Only the header of `input` is read.
+    pub fn unwrap_shares<R: Read, W: Write>(&self, input: &mut R, combiner: UserPublicKey, output: &mut W) -> Result<usize, Error> {
+        let header = Header::read(input)?;
+        let shares = self.own_shares(&header);
+        if shares.is_empty() {
+            return Err(Error::NotAShareHolder);
+        }
+        let content = sharefile::encode(&sharefile::file_id(&header), &shares);
+        Encryptor::new().recipient(combiner).encrypt(&mut &content[..], output)?;
+        Ok(shares.len())
+    }
+
+    /// Decrypts everything in `input` into `output`.
+    /// On an error, e.g. a bad signature or a truncated file, part of the plaintext may already be in `output`,
+    /// it's only verified once this returns `Ok`.
+    pub fn decrypt<R: Read, W: Write>(&self, input: &mut R, output: &mut W) -> Result<(), Error> {
+        let mut reader = self.wrap_input(input)?;
+        io::copy(&mut reader, output)?;
+        Ok(())
+    }
+
+    /// Decrypts `input` without keeping the plaintext, which authenticates the header and every chunk of the payload.
+    /// Returns the length of the plaintext.
+    pub fn verify<R: Read>(&self, input: &mut R) -> Result<u64, Error> {
+        let mut reader = self.wrap_input(input)?;
+        Ok(io::copy(&mut reader, &mut io::sink())?)
+    }
+
+    /// Reads the header of a file encrypted with `Encryptor::threshold`, and returns how many distinct shares
+    /// of its file key the keys of this decryptor hold, and how many are needed to decrypt it.
+    pub fn shares<R: Read>(&self, input: &mut R) -> Result<(usize, u8), Error> {
+        let header = Header::read(input)?;
+        let threshold = header.stanzas.iter().find(|s| s.kind == header::STANZA_X25519_SHARE).and_then(|s| {
+            let hint_len = if header.flags & header::FLAG_RECIPIENT_HINTS != 0 { HINT_LEN } else { 0 };
+            s.body.get(hint_len).cloned()
+        });
+        Ok((self.find_shares(&header).len(), threshold.unwrap_or(0)))
+    }
+
+    /// Reads the header from `input` and returns a reader that decrypts the rest of it.
+    /// The bytes it returns are unverified until its `read` returned EOF, see `StreamReader`.
+    pub fn wrap_input<R: Read>(&self, mut input: R) -> Result<StreamReader<R>, Error> {
+        let header = Header::read(&mut input)?;
+        let file_key = self.find_file_key(&header)?;
+        header.verify(&file_key)?;
+        let signed = header.flags & header::FLAG_SIGNED != 0;
+        if !signed && self.expected_signer.is_some() {
+            return Err(Error::BadSignature);
+        }
+
+        let reader = StreamReader::new(&file_key, header.scheme, input)?;
+        if signed {
+            Ok(reader.with_verifier(Verifier::new(self.expected_signer, &header.fixed_bytes())))
+        } else {
+            Ok(reader)
+        }
+    }
+
+    /// Copies `input` into `output` with `recipients` added to its header, the payload isn't re-encrypted.
+    /// The secret of the header's ephemeral key isn't kept, so each of them gets a `STANZA_ADDED` stanza wrapped with
+    /// an ephemeral key of its own, and a recipient that can already decrypt the file gets a second stanza.
+    pub fn add_recipients<K, R, W>(&self, recipients: &[K], input: &mut R, output: &mut W) -> Result<(), Error>
+    where
+        K: Recipient,
+        R: Read,
+        W: Write,
+    {
+        let mut seen = HashSet::new();
+        let recipients: Vec<_> =
+            recipients.iter().filter(|key| key.fingerprint().is_none_or(|fingerprint| seen.insert(fingerprint))).collect();
+        self.rewrite_header(input, output, |header, file_key| {
+            let hints = header.flags & header::FLAG_RECIPIENT_HINTS != 0;
+            for recipient in recipients {
+                let ephemeral = x25519::EphemeralKey::new()?;
+                let ctx = WrapContext { ephemeral: &ephemeral, sender: None, scheme: header.scheme, hints };
+                header.stanzas.push(Stanza::added(&ephemeral.get_public(), &recipient.wrap_file_key(file_key, &ctx)?));
+            }
+            Ok(())
+        })
+    }
+
+    /// Copies `input` into `output` without the stanzas `stanzas` refer to, by index or by recipient hint as listed by `inspect`.
+    /// The payload isn't re-encrypted, so a removed recipient that kept the old file (or its file key) can still decrypt it.
+    pub fn remove_stanzas<R: Read, W: Write>(&self, stanzas: &[StanzaRef], input: &mut R, output: &mut W) -> Result<(), Error> {
+        self.rewrite_header(input, output, |header, _| {
+            let hints = header.flags & header::FLAG_RECIPIENT_HINTS != 0;
+            let mut removed = vec![false; header.stanzas.len()];
+            for stanza_ref in stanzas {
+                let mut found = false;
+                for (index, stanza) in header.stanzas.iter().enumerate() {
+                    if stanza_ref.matches(index, stanza, hints) {
+                        removed[index] = true;
+                        found = true;
+                    }
+                }
+                if !found {
+                    return Err(Error::NoSuchStanza(stanza_ref.to_string()));
+                }
+            }
+            let kept = header.stanzas.drain(..).zip(removed).filter(|(_, removed)| !removed).map(|(stanza, _)| stanza).collect();
+            header.stanzas = kept;
+            if header.stanzas.is_empty() {
+                return Err(Error::NoRecipients);
+            }
+            Ok(())
+        })
+    }
+
+    /// Unwraps the file key, lets `edit` change the header, then writes the resealed header and copies the payload as is.
+    fn rewrite_header<R, W, F>(&self, input: &mut R, output: &mut W, edit: F) -> Result<(), Error>
+    where
+        R: Read,
+        W: Write,
+        F: FnOnce(&mut Header, &Secret) -> Result<(), Error>,
+    {
+        let mut header = Header::read(&mut *input)?;
+        let file_key = self.find_file_key(&header)?;
+        header.verify(&file_key)?;
+        edit(&mut header, &file_key)?;
+        header.seal(&file_key)?;
+        header.write(output)?;
+        io::copy(input, output)?;
+        Ok(())
+    }
+
+    fn find_file_key(&self, header: &Header) -> Result<Secret, Error> {
+        if self.expected_sender.is_some() && header.stanzas.len() != 1 {
+            return Err(Error::BadSender);
+        }
+        // Stanzas added to an existing file have an ephemeral key of their own, keys and identities look for theirs in them too.
+        let added = header.added_headers();
+        let contexts: Vec<_> = std::iter::once(header)
+            .chain(&added)
+            .map(|header| UnwrapContext { header, expected_sender: self.expected_sender.as_ref() })
+            .collect();
+        // A stanza that fails to unwrap doesn't keep the other keys from being tried, its error is reported if none of them works.
+        let mut error = None;
+        for key in &self.keys {
+            for ctx in &contexts {
+                if let Some(file_key) = try_unwrap(key, ctx, &mut error) {
+                    return Ok(file_key);
+                }
+            }
+        }
+        if self.expected_sender.is_some() {
+            return Err(error.unwrap_or(Error::BadSender));
+        }
+        for identity in &self.identities {
+            for ctx in &contexts {
+                if let Some(file_key) = try_unwrap(&**identity, ctx, &mut error) {
+                    return Ok(file_key);
+                }
+            }
+        }
+        let ctx = &contexts[0];
+        let shares = self.find_shares(header);
+        if shares.first().is_some_and(|share| shares.len() >= usize::from(share.threshold)) {
+            return shamir::combine(&shares);
+        }
+        // Passphrases come last, scrypt is slow on purpose.
+        for passphrase in &self.passphrases {
+            let passphrase = Passphrase::new(passphrase.clone(), self.max_work_factor);
+            if let Some(file_key) = try_unwrap(&passphrase, ctx, &mut error) {
+                return Ok(file_key);
+            }
+        }
+        if !shares.is_empty() {
+            // Reports how many more shares are needed.
+            return shamir::combine(&shares);
+        }
+        Err(error.unwrap_or(Error::BadKey))
+    }
+
+    /// The distinct shares of the file key that the keys of this decryptor can unwrap, or that came from share files.
+    fn find_shares(&self, header: &Header) -> Vec<Share> {
+        let mut shares = self.own_shares(header);
+        let file_id = sharefile::file_id(header);
+        for (_, share) in self.shares.iter().filter(|(id, _)| *id == file_id) {
+            if !shares.iter().any(|other| other.index == share.index) {
+                shares.push(share.clone());
+            }
+        }
+        shares
+    }
+
+    /// The distinct shares of the file key that the keys of this decryptor can unwrap.
+    fn own_shares(&self, header: &Header) -> Vec<Share> {
+        let hints = header.flags & header::FLAG_RECIPIENT_HINTS != 0;
+        let mut shares: Vec<Share> = Vec::new();
+        for key in &self.keys {
+            let shared = key.derive_secret(&header.ephemeral, 32);
+            for stanza in header.stanzas.iter().filter(|s| s.kind == header::STANZA_X25519_SHARE) {
+                if let Some(share) = unwrap_share(stanza, &shared, hints, header.scheme) {
+                    if !shares.iter().any(|other| other.index == share.index) {
+                        shares.push(share);
+                    }
+                }
+            }
+        }
+        shares
+    }
 }
 
-fn take<R: Read>(reader: &mut R) -> io::Result<u8> {
-    let mut b = [0];
-    reader.read_exact(&mut b)?;
-    Ok(b[0])
+/// Encrypts `input` into `output` for every key in `keys`.
+pub fn encrypt_file_with_keys<R, W, K>(input: &mut R, keys: &[K], output: &mut W, scheme: Scheme) -> Result<(), Error>
+where
+    R: Read,
+    W: Write,
+    K: Recipient + Clone + Send + Sync + 'static,
+{
+    Encryptor::new().recipients(keys.iter().cloned()).scheme(scheme).encrypt(input, output)
 }
 
-fn find_encrypted_key<R: Read>(f: &mut R, shared: Secret, amount: u8, scheme: Scheme) -> (Option<Secret>, u8) {
-    let key_size = scheme.get_encrypted_key_size();
-    for i in 1..=amount {
-        let mut encrypted_key = vec![0u8; key_size];
-        match f.read_exact(&mut encrypted_key).ok() {
-            None => return (None, amount - i),
-            Some(()) => (),
-        };
-        match encryption::decrypt_data(shared.as_ref(), encrypted_key, scheme) {
-            Err(_) => continue,
-            Ok(key) => return (Some(Secret::from_vec(key)), amount - i),
+/// Decrypts `input` into `output` with `key`.
+pub fn decrypt_file_with_keys<R, W, I>(input: &mut R, key: I, output: &mut W) -> Result<(), Error>
+where
+    R: Read,
+    W: Write,
+    I: Identity + Send + Sync + 'static,
+{
+    Decryptor::with_identity(key).decrypt(input, output)
+}
+
+/// Unwraps the file key with `identity`, an error is kept in `error` (unless it already holds one) so other identities can still be tried.
+fn try_unwrap(identity: &dyn Identity, ctx: &UnwrapContext, error: &mut Option<Error>) -> Option<Secret> {
+    match identity.unwrap_file_key(ctx) {
+        Ok(file_key) => file_key,
+        Err(err) => {
+            error.get_or_insert(err);
+            None
         }
     }
-    (None, 0)
+}
+
+fn wrap_share(share: &Share, shared: &Secret, hints: bool, scheme: Scheme) -> Result<Stanza, Error> {
+    let mut body = if hints { recipient_hint(shared).to_vec() } else { Vec::new() };
+    body.extend_from_slice(&[share.threshold, share.index]);
+    body.extend(encryption::encrypt_data(shared.as_ref(), share.value.clone().into_vec(), scheme)?);
+    Ok(Stanza::new(header::STANZA_X25519_SHARE, body))
+}
+
+/// Returns `None` if the share isn't wrapped to `shared`.
+fn unwrap_share(stanza: &Stanza, shared: &Secret, hints: bool, scheme: Scheme) -> Option<Share> {
+    let body = if hints { stanza.body.strip_prefix(&recipient_hint(shared)[..])? } else { &stanza.body[..] };
+    if body.len() != 2 + scheme.get_encrypted_key_size() {
+        return None;
+    }
+    let value = encryption::decrypt_data(shared.as_ref(), body[2..].to_vec(), scheme).ok()?;
+    Some(Share { threshold: body[0], index: body[1], value: Secret::from_vec(value) })
 }
 
 #[cfg(test)]
@@ -76,6 +537,7 @@ mod tests {
     use super::*;
     use crate::tests::{generate_random_keys, get_rand_file};
     use rand::{seq::SliceRandom, thread_rng, Rng};
+    use std::io::{Seek, SeekFrom};
     use tempfile::tempfile;
 
     #[test]
@@ -91,13 +553,13 @@ mod tests {
         let mut original = get_rand_file();
         let mut encrypted = tempfile().unwrap();
         let mut decrypted = tempfile().unwrap();
-        let keys = generate_random_keys(rng.gen());
+        let keys = generate_random_keys(rng.gen_range(1, 256));
         let decrypt_with = keys.choose(&mut rng).unwrap().0.clone();
-        let pub_keys = keys.iter().map(|(_, p)| p).cloned().collect();
+        let pub_keys: Vec<_> = keys.iter().map(|(_, p)| *p).collect();
 
-        encrypt_file_with_keys(&mut original, pub_keys, &mut encrypted, scheme).unwrap();
+        encrypt_file_with_keys(&mut original, &pub_keys, &mut encrypted, scheme).unwrap();
         encrypted.seek(SeekFrom::Start(0)).unwrap();
-        decrypt_file_with_keys(&mut encrypted, decrypt_with, &mut decrypted, scheme).unwrap();
+        decrypt_file_with_keys(&mut encrypted, decrypt_with, &mut decrypted).unwrap();
 
         original.seek(SeekFrom::Start(0)).unwrap();
         decrypted.seek(SeekFrom::Start(0)).unwrap();
@@ -108,4 +570,592 @@ mod tests {
         assert_eq!(before, after);
     }
 
+    #[test]
+    fn scheme_is_detected_from_header() {
+        let mut original = get_rand_file();
+        let mut encrypted = tempfile().unwrap();
+        let keys = generate_random_keys(1);
+        let pub_keys: Vec<_> = keys.iter().map(|(_, p)| *p).collect();
+
+        encrypt_file_with_keys(&mut original, &pub_keys, &mut encrypted, Scheme::Chacha20Poly1305).unwrap();
+        encrypted.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(Header::read(&mut encrypted).unwrap().scheme, Scheme::Chacha20Poly1305);
+    }
+
+    #[test]
+    fn encryption_decryption_in_memory() {
+        let original = b"Streams don't need to be files".to_vec();
+        let keys = generate_random_keys(3);
+        let pub_keys: Vec<_> = keys.iter().map(|(_, p)| *p).collect();
+        let mut encrypted = Vec::new();
+        let mut decrypted = Vec::new();
+
+        let encryptor = Encryptor::new().recipients(pub_keys).scheme(Scheme::Chacha20Poly1305);
+        encryptor.encrypt(&mut &original[..], &mut encrypted).unwrap();
+        Decryptor::new(keys[1].0.clone()).decrypt(&mut &encrypted[..], &mut decrypted).unwrap();
+        assert_eq!(original, decrypted);
+    }
+
+    #[test]
+    fn thousands_of_recipients() {
+        let keys = generate_random_keys(3000);
+        let pub_keys: Vec<_> = keys.iter().map(|(_, p)| *p).collect();
+        let mut encrypted = Vec::new();
+        let mut decrypted = Vec::new();
+
+        encrypt_file_with_keys(&mut &b"for the whole company"[..], &pub_keys, &mut encrypted, Scheme::AES256GCM).unwrap();
+        let (last, _) = keys.last().unwrap();
+        decrypt_file_with_keys(&mut &encrypted[..], last.clone(), &mut decrypted).unwrap();
+        assert_eq!(decrypted, b"for the whole company");
+    }
+
+    #[test]
+    fn too_many_recipients() {
+        // Distinct keys, duplicates would be merged. They don't need to be valid points.
+        let keys: Vec<_> = (0..=header::MAX_RECIPIENTS as u32)
+            .map(|i| {
+                let mut bytes = [0u8; 32];
+                bytes[..4].copy_from_slice(&i.to_le_bytes());
+                UserPublicKey::from(bytes)
+            })
+            .collect();
+        let res = encrypt_file_with_keys(&mut &b""[..], &keys, &mut Vec::new(), Scheme::AES256GCM);
+        assert!(matches!(res, Err(Error::TooManyRecipients(n)) if n == header::MAX_RECIPIENTS + 1));
+    }
+
+    #[test]
+    fn passphrase_recipients() {
+        let keys = generate_random_keys(2);
+        let encryptor = Encryptor::new().recipient(keys[0].1).passphrase("hunter2").work_factor(10);
+        let mut encrypted = Vec::new();
+        encryptor.encrypt(&mut &b"shared with a friend"[..], &mut encrypted).unwrap();
+
+        for decryptor in [Decryptor::with_passphrase("hunter2"), Decryptor::new(keys[0].0.clone())] {
+            let mut decrypted = Vec::new();
+            decryptor.decrypt(&mut &encrypted[..], &mut decrypted).unwrap();
+            assert_eq!(decrypted, b"shared with a friend");
+        }
+
+        let res = Decryptor::with_passphrase("wrong").key(keys[1].0.clone()).decrypt(&mut &encrypted[..], &mut Vec::new());
+        assert!(matches!(res, Err(Error::BadKey)));
+        // The decryptor decides how much work a passphrase is worth, not the file.
+        let res = Decryptor::with_passphrase("hunter2").max_work_factor(9).verify(&mut &encrypted[..]);
+        assert!(matches!(res, Err(Error::BadWorkFactor(10))));
+
+        let res = encryptor.passphrase("letmein").encrypt(&mut &b""[..], &mut Vec::new());
+        assert!(matches!(res, Err(Error::MalformedHeader(_))));
+    }
+
+    #[test]
+    fn modified_header() {
+        let keys = generate_random_keys(3);
+        let pub_keys: Vec<_> = keys.iter().map(|(_, p)| *p).collect();
+        let mut encrypted = Vec::new();
+        encrypt_file_with_keys(&mut &b"don't touch"[..], &pub_keys, &mut encrypted, Scheme::AES256GCM).unwrap();
+
+        let modify = |f: &dyn Fn(&mut Header)| {
+            let mut input = &encrypted[..];
+            let mut header = Header::read(&mut input).unwrap();
+            f(&mut header);
+            let mut modified = Vec::new();
+            header.write(&mut modified).unwrap();
+            modified.extend_from_slice(input);
+            decrypt_file_with_keys(&mut &modified[..], keys[0].0.clone(), &mut Vec::new())
+        };
+
+        assert!(modify(&|_| ()).is_ok());
+        assert!(matches!(modify(&|h| drop(h.stanzas.pop())), Err(Error::HeaderMismatch)));
+        assert!(matches!(modify(&|h| h.stanzas.swap(1, 2)), Err(Error::HeaderMismatch)));
+        assert!(matches!(modify(&|h| h.stanzas.push(Stanza::new(0xff, vec![1, 2, 3]))), Err(Error::HeaderMismatch)));
+    }
+
+    #[test]
+    fn recipient_hints() {
+        let keys = generate_random_keys(20);
+        let pub_keys: Vec<_> = keys.iter().map(|(_, p)| *p).collect();
+        let mut encrypted = Vec::new();
+        let encryptor = Encryptor::new().recipients(pub_keys).recipient_hints(true).work_factor(10).passphrase("pass");
+        encryptor.encrypt(&mut &b"straight to the point"[..], &mut encrypted).unwrap();
+
+        let header = Header::read(&mut &encrypted[..]).unwrap();
+        assert_eq!(header.flags, header::FLAG_RECIPIENT_HINTS);
+        assert_eq!(header.stanzas[0].body.len(), HINT_LEN + Scheme::AES256GCM.get_encrypted_key_size());
+
+        for (key, _) in &keys {
+            let mut decrypted = Vec::new();
+            decrypt_file_with_keys(&mut &encrypted[..], key.clone(), &mut decrypted).unwrap();
+            assert_eq!(decrypted, b"straight to the point");
+        }
+        let mut decrypted = Vec::new();
+        Decryptor::with_passphrase("pass").decrypt(&mut &encrypted[..], &mut decrypted).unwrap();
+        assert_eq!(decrypted, b"straight to the point");
+
+        let (stranger, _) = generate_random_keys(1).remove(0);
+        assert!(matches!(decrypt_file_with_keys(&mut &encrypted[..], stranger, &mut Vec::new()), Err(Error::BadKey)));
+
+        // A corrupted wrapped key is reported as such instead of as a wrong key.
+        let mut input = &encrypted[..];
+        let mut header = Header::read(&mut input).unwrap();
+        *header.stanzas[7].body.last_mut().unwrap() ^= 1;
+        let mut corrupted = Vec::new();
+        header.write(&mut corrupted).unwrap();
+        corrupted.extend_from_slice(input);
+        let res = decrypt_file_with_keys(&mut &corrupted[..], keys[7].0.clone(), &mut Vec::new());
+        assert!(matches!(res, Err(Error::BadStanza)));
+    }
+
+    #[test]
+    fn add_and_remove_recipients() {
+        for &hints in &[false, true] {
+            let keys = generate_random_keys(5);
+            let mut encrypted = Vec::new();
+            let encryptor = Encryptor::new().recipients(keys[..3].iter().map(|(_, p)| *p)).recipient_hints(hints);
+            encryptor.encrypt(&mut &b"payload stays the same"[..], &mut encrypted).unwrap();
+            let payload = |file: &[u8]| {
+                let mut input = file;
+                Header::read(&mut input).unwrap();
+                input.to_vec()
+            };
+            let decrypt = |file: &[u8], key: &UserSecretKey| {
+                let mut decrypted = Vec::new();
+                decrypt_file_with_keys(&mut &file[..], key.clone(), &mut decrypted).map(|_| decrypted)
+            };
+
+            // Regular and hybrid keys can be mixed, each gets a stanza with an ephemeral key of its own.
+            let hybrid = HybridSecretKey::new().unwrap();
+            let new_keys: Vec<Box<dyn Recipient>> =
+                vec![Box::new(keys[3].1), Box::new(hybrid.get_public()), Box::new(keys[4].1), Box::new(keys[3].1)];
+            let mut added = Vec::new();
+            Decryptor::new(keys[1].0.clone()).add_recipients(&new_keys, &mut &encrypted[..], &mut added).unwrap();
+            let header = Header::read(&mut &added[..]).unwrap();
+            let ephemerals: HashSet<_> = header.stanzas.iter().filter_map(Stanza::unwrap_added).map(|(e, _)| *e.as_bytes()).collect();
+            assert_eq!(header.stanzas.len(), 6);
+            assert_eq!(ephemerals.len(), 3);
+            assert!(!ephemerals.contains(header.ephemeral.as_bytes()));
+            assert_eq!(payload(&added), payload(&encrypted));
+            for (key, _) in &keys {
+                assert_eq!(decrypt(&added, key).unwrap(), b"payload stays the same");
+            }
+            let mut decrypted = Vec::new();
+            Decryptor::with_identity(hybrid.clone()).decrypt(&mut &added[..], &mut decrypted).unwrap();
+            assert_eq!(decrypted, b"payload stays the same");
+            // A recipient added later can add more recipients in turn.
+            let mut added_again = Vec::new();
+            Decryptor::with_identity(hybrid.clone()).add_recipients(&[keys[0].1], &mut &added[..], &mut added_again).unwrap();
+            assert_eq!(Header::read(&mut &added_again[..]).unwrap().stanzas.len(), 7);
+
+            // Stanzas are removed by index, or by hint when the file has them, whatever their kind.
+            let infos: Vec<_> = header.stanzas.iter().map(|stanza| inspect::stanza_info(stanza, hints)).collect();
+            let refs: Vec<_> = [0, 3, 4]
+                .iter()
+                .map(|&i| match infos[i].hint {
+                    Some(hint) => StanzaRef::Hint(hint),
+                    None => StanzaRef::Index(i),
+                })
+                .collect();
+            assert_eq!(infos[4].kind, header::STANZA_X25519_HYBRID);
+            assert!(infos[3].added && infos[4].added && !infos[0].added);
+            assert_eq!(hints, infos[4].hint.is_some());
+            let mut removed = Vec::new();
+            Decryptor::new(keys[4].0.clone()).remove_stanzas(&refs, &mut &added[..], &mut removed).unwrap();
+            assert_eq!(payload(&removed), payload(&encrypted));
+            assert_eq!(Header::read(&mut &removed[..]).unwrap().stanzas.len(), 3);
+            assert!(matches!(decrypt(&removed, &keys[0].0), Err(Error::BadKey)));
+            assert!(matches!(decrypt(&removed, &keys[3].0), Err(Error::BadKey)));
+            assert!(matches!(Decryptor::with_identity(hybrid).verify(&mut &removed[..]), Err(Error::BadKey)));
+            assert!(decrypt(&removed, &keys[1].0).is_ok());
+            assert!(decrypt(&removed, &keys[4].0).is_ok());
+
+            let decryptor = Decryptor::new(keys[1].0.clone());
+            let res = decryptor.remove_stanzas(&[StanzaRef::Index(3)], &mut &removed[..], &mut Vec::new());
+            assert!(matches!(res, Err(Error::NoSuchStanza(ref s)) if s == "3"));
+            let res = decryptor.remove_stanzas(&[StanzaRef::Hint([0u8; HINT_LEN])], &mut &removed[..], &mut Vec::new());
+            assert!(matches!(res, Err(Error::NoSuchStanza(ref s)) if s == "0x0000000000000000"));
+            let all = [StanzaRef::Index(0), StanzaRef::Index(1), StanzaRef::Index(2)];
+            let res = decryptor.remove_stanzas(&all, &mut &removed[..], &mut Vec::new());
+            assert!(matches!(res, Err(Error::NoRecipients)));
+            let res = Decryptor::new(keys[0].0.clone()).add_recipients(&[keys[0].1], &mut &removed[..], &mut Vec::new());
+            assert!(matches!(res, Err(Error::BadKey)));
+        }
+    }
+
+    #[test]
+    fn remove_any_stanza_kind() {
+        let keys = generate_random_keys(4);
+        let (sender, sender_public) = generate_random_keys(1).remove(0);
+        let hybrid = HybridSecretKey::new().unwrap();
+        let mut encrypted = Vec::new();
+        let encryptor = Encryptor::new().recipient(keys[0].1).recipient(hybrid.get_public()).recipient_hints(true);
+        let encryptor = encryptor.threshold(2, keys[1..].iter().map(|(_, p)| *p));
+        encryptor.encrypt(&mut &b"all kinds"[..], &mut encrypted).unwrap();
+        let mut authenticated = Vec::new();
+        let encryptor = Encryptor::new().recipient(keys[0].1).recipient_hints(true).authenticate_as(sender);
+        encryptor.encrypt(&mut &b"all kinds"[..], &mut authenticated).unwrap();
+        let header = Header::read(&mut &authenticated[..]).unwrap();
+        assert_eq!(header.stanzas[0].kind, header::STANZA_X25519_AUTHENTICATED);
+
+        // The authenticated stanza is found by its hint, and removing it leaves a file nobody can decrypt.
+        let hint = inspect::stanza_info(&header.stanzas[0], true).hint.unwrap();
+        let decryptor = Decryptor::new(keys[0].0.clone()).expect_sender(sender_public);
+        let res = decryptor.remove_stanzas(&[StanzaRef::Hint(hint)], &mut &authenticated[..], &mut Vec::new());
+        assert!(matches!(res, Err(Error::NoRecipients)));
+
+        // Removing the hybrid stanza and a share by hint, and another share by index.
+        let header = Header::read(&mut &encrypted[..]).unwrap();
+        let kinds: Vec<_> = header.stanzas.iter().map(|s| s.kind).collect();
+        let share = header::STANZA_X25519_SHARE;
+        assert_eq!(kinds, [header::STANZA_X25519, header::STANZA_X25519_HYBRID, share, share, share]);
+        let hint = |i: usize| StanzaRef::Hint(inspect::stanza_info(&header.stanzas[i], true).hint.unwrap());
+        let mut removed = Vec::new();
+        let refs = [hint(1), hint(2), StanzaRef::Index(3)];
+        Decryptor::new(keys[0].0.clone()).remove_stanzas(&refs, &mut &encrypted[..], &mut removed).unwrap();
+        let kinds: Vec<_> = Header::read(&mut &removed[..]).unwrap().stanzas.iter().map(|s| s.kind).collect();
+        assert_eq!(kinds, [header::STANZA_X25519, share]);
+        assert!(matches!(Decryptor::with_identity(hybrid).verify(&mut &removed[..]), Err(Error::BadKey)));
+        let shares = Decryptor::new(keys[1].0.clone()).key(keys[2].0.clone()).key(keys[3].0.clone());
+        assert!(matches!(shares.verify(&mut &removed[..]), Err(Error::NotEnoughShares(1, 2))));
+    }
+
+    #[test]
+    fn verify_without_output() {
+        let keys = generate_random_keys(2);
+        let data = vec![9u8; 3 * stream::CHUNK_SIZE];
+        let mut encrypted = Vec::new();
+        Encryptor::new().recipient(keys[0].1).encrypt(&mut &data[..], &mut encrypted).unwrap();
+        let decryptor = Decryptor::new(keys[0].0.clone());
+        assert_eq!(decryptor.verify(&mut &encrypted[..]).unwrap(), data.len() as u64);
+
+        let mut corrupted = encrypted.clone();
+        let second_chunk = encrypted.len() - 2 * (stream::CHUNK_SIZE + 16);
+        corrupted[second_chunk] ^= 1;
+        assert!(matches!(decryptor.verify(&mut &corrupted[..]), Err(Error::CorruptedChunk(1))));
+        assert!(matches!(decryptor.verify(&mut &encrypted[..encrypted.len() - 1]), Err(Error::CorruptedChunk(2))));
+        // Cut right after a chunk, that chunk isn't corrupted but the stream is truncated.
+        let boundary = encrypted.len() - (stream::CHUNK_SIZE + 16);
+        assert!(matches!(decryptor.verify(&mut &encrypted[..boundary]), Err(Error::Truncated)));
+        assert!(matches!(Decryptor::new(keys[1].0.clone()).verify(&mut &encrypted[..]), Err(Error::BadKey)));
+    }
+
+    #[test]
+    fn signed_files() {
+        let keys = generate_random_keys(2);
+        let signer = UserSigningKey::new().unwrap();
+        let verifying = signer.get_verifying();
+        // The signature is split between the last two chunks.
+        let data = vec![3u8; stream::CHUNK_SIZE - 10];
+        let mut signed = Vec::new();
+        Encryptor::new().recipient(keys[0].1).sign_with(signer).encrypt(&mut &data[..], &mut signed).unwrap();
+        let mut unsigned = Vec::new();
+        Encryptor::new().recipient(keys[0].1).encrypt(&mut &data[..], &mut unsigned).unwrap();
+
+        let mut reader = Decryptor::new(keys[0].0.clone()).wrap_input(&signed[..]).unwrap();
+        let mut decrypted = Vec::new();
+        reader.read_to_end(&mut decrypted).unwrap();
+        assert_eq!(decrypted, data);
+        assert_eq!(reader.signer(), Some(verifying));
+
+        let decryptor = Decryptor::new(keys[0].0.clone()).expect_signer(verifying);
+        let mut decrypted = Vec::new();
+        decryptor.decrypt(&mut &signed[..], &mut decrypted).unwrap();
+        assert_eq!(decrypted, data);
+        assert!(matches!(decryptor.verify(&mut &unsigned[..]), Err(Error::BadSignature)));
+        let stranger = UserSigningKey::new().unwrap().get_verifying();
+        let res = Decryptor::new(keys[0].0.clone()).expect_signer(stranger).verify(&mut &signed[..]);
+        assert!(matches!(res, Err(Error::BadSignature)));
+
+        // Recipients can be added without breaking the signature.
+        let mut added = Vec::new();
+        Decryptor::new(keys[0].0.clone()).add_recipients(&[keys[1].1], &mut &signed[..], &mut added).unwrap();
+        let decryptor = Decryptor::new(keys[1].0.clone()).expect_signer(verifying);
+        assert_eq!(decryptor.verify(&mut &added[..]).unwrap(), data.len() as u64);
+    }
+
+    #[test]
+    fn authenticated_sender() {
+        for &hints in &[false, true] {
+            let keys = generate_random_keys(2);
+            let (sender, sender_public) = generate_random_keys(1).remove(0);
+            let mut encrypted = Vec::new();
+            let encryptor = Encryptor::new().recipient(keys[0].1).recipient_hints(hints).authenticate_as(sender.clone());
+            encryptor.encrypt(&mut &b"from a friend"[..], &mut encrypted).unwrap();
+
+            let mut decrypted = Vec::new();
+            Decryptor::new(keys[0].0.clone()).expect_sender(sender_public).decrypt(&mut &encrypted[..], &mut decrypted).unwrap();
+            assert_eq!(decrypted, b"from a friend");
+            let (impostor, impostor_public) = generate_random_keys(1).remove(0);
+            let res = Decryptor::new(keys[0].0.clone()).expect_sender(impostor_public).verify(&mut &encrypted[..]);
+            assert!(matches!(res, Err(Error::BadSender)));
+            // Without an expected sender the authenticated stanza can't be unwrapped at all.
+            assert!(matches!(Decryptor::new(keys[0].0.clone()).verify(&mut &encrypted[..]), Err(Error::BadKey)));
+
+            // An unauthenticated file from someone else isn't accepted either.
+            let mut forged = Vec::new();
+            Encryptor::new().recipient(keys[0].1).authenticate_as(impostor).encrypt(&mut &b"trust me"[..], &mut forged).unwrap();
+            let res = Decryptor::new(keys[0].0.clone()).expect_sender(sender_public).verify(&mut &forged[..]);
+            assert!(matches!(res, Err(Error::BadSender)));
+
+            // Nor is a file with recipients added later, whoever added them knows the file key.
+            let mut added = Vec::new();
+            let decryptor = Decryptor::new(keys[0].0.clone()).expect_sender(sender_public);
+            decryptor.add_recipients(&[keys[1].1], &mut &encrypted[..], &mut added).unwrap();
+            assert!(Decryptor::new(keys[1].0.clone()).verify(&mut &added[..]).is_ok());
+            assert!(matches!(decryptor.verify(&mut &added[..]), Err(Error::BadSender)));
+
+            // Anyone else holding the file key could forge the payload, so there's no second recipient to hold it.
+            let encryptor = Encryptor::new().recipients(keys.iter().map(|(_, p)| *p)).authenticate_as(sender.clone());
+            let res = encryptor.encrypt(&mut &b""[..], &mut Vec::new());
+            assert!(matches!(res, Err(Error::SenderWithManyRecipients(2))));
+            let encryptor = Encryptor::new().recipient(keys[0].1).work_factor(10).passphrase("pass").authenticate_as(sender.clone());
+            let res = encryptor.encrypt(&mut &b""[..], &mut Vec::new());
+            assert!(matches!(res, Err(Error::SenderWithManyRecipients(2))));
+        }
+    }
+
+    #[test]
+    fn co_recipient_cannot_forge_sender() {
+        let keys = generate_random_keys(2);
+        let (sender, sender_public) = generate_random_keys(1).remove(0);
+        // A header authenticated by the sender for two recipients, as an older version would write it.
+        let file_key = Secret::generate32().unwrap();
+        let ephemeral = x25519::EphemeralKey::new().unwrap();
+        let mut header = Header::new(Scheme::AES256GCM, ephemeral.get_public());
+        let ctx = WrapContext { ephemeral: &ephemeral, sender: Some(&sender), scheme: Scheme::AES256GCM, hints: false };
+        for (_, public) in &keys {
+            header.stanzas.push(public.wrap_file_key(&file_key, &ctx).unwrap());
+        }
+        header.seal(&file_key).unwrap();
+
+        // The second recipient unwraps the file key and puts its own payload behind the sender's header.
+        let mut forged = Vec::new();
+        header.write(&mut forged).unwrap();
+        let mut writer = StreamWriter::new(&file_key, Scheme::AES256GCM, forged).unwrap();
+        writer.write_all(b"pay the bearer").unwrap();
+        let forged = writer.finish().unwrap();
+
+        let res = Decryptor::new(keys[0].0.clone()).expect_sender(sender_public).verify(&mut &forged[..]);
+        assert!(matches!(res, Err(Error::BadSender)));
+    }
+
+    #[test]
+    fn threshold_decryption() {
+        for &hints in &[false, true] {
+            let officers = generate_random_keys(5);
+            let (owner, owner_public) = generate_random_keys(1).remove(0);
+            let mut encrypted = Vec::new();
+            let encryptor =
+                Encryptor::new().recipient(owner_public).threshold(3, officers.iter().map(|(_, p)| *p)).recipient_hints(hints);
+            encryptor.encrypt(&mut &b"launch codes"[..], &mut encrypted).unwrap();
+
+            let decryptor = |indexes: &[usize]| {
+                let mut decryptor = Decryptor::new(officers[indexes[0]].0.clone());
+                for &i in &indexes[1..] {
+                    decryptor = decryptor.key(officers[i].0.clone());
+                }
+                decryptor
+            };
+            for indexes in &[[0, 1, 2], [4, 2, 0], [1, 3, 4]] {
+                let mut decrypted = Vec::new();
+                decryptor(indexes).decrypt(&mut &encrypted[..], &mut decrypted).unwrap();
+                assert_eq!(decrypted, b"launch codes");
+                assert_eq!(decryptor(indexes).shares(&mut &encrypted[..]).unwrap(), (3, 3));
+            }
+            assert!(matches!(decryptor(&[0, 3]).verify(&mut &encrypted[..]), Err(Error::NotEnoughShares(2, 3))));
+            assert_eq!(decryptor(&[0, 3]).shares(&mut &encrypted[..]).unwrap(), (2, 3));
+            // The same key twice is still a single share.
+            assert!(matches!(decryptor(&[1, 1, 1]).verify(&mut &encrypted[..]), Err(Error::NotEnoughShares(1, 3))));
+            // Regular recipients don't need the share holders.
+            assert!(Decryptor::new(owner).verify(&mut &encrypted[..]).is_ok());
+            assert!(matches!(Decryptor::new(generate_random_keys(1).remove(0).0).verify(&mut &encrypted[..]), Err(Error::BadKey)));
+        }
+
+        let keys = generate_random_keys(2);
+        let res = Encryptor::new().threshold(3, keys.iter().map(|(_, p)| *p)).encrypt(&mut &b""[..], &mut Vec::new());
+        assert!(matches!(res, Err(Error::BadThreshold(3, 2))));
+    }
+
+    #[test]
+    fn share_files() {
+        let officers = generate_random_keys(3);
+        let (combiner, combiner_public) = generate_random_keys(1).remove(0);
+        let mut encrypted = Vec::new();
+        Encryptor::new().threshold(2, officers.iter().map(|(_, p)| *p)).encrypt(&mut &b"split custody"[..], &mut encrypted).unwrap();
+
+        let share_files: Vec<Vec<u8>> = officers
+            .iter()
+            .map(|(key, _)| {
+                let mut share_file = Vec::new();
+                let shares = Decryptor::new(key.clone()).unwrap_shares(&mut &encrypted[..], combiner_public, &mut share_file).unwrap();
+                assert_eq!(shares, 1);
+                share_file
+            })
+            .collect();
+        let res = Decryptor::new(combiner.clone()).unwrap_shares(&mut &encrypted[..], combiner_public, &mut Vec::new());
+        assert!(matches!(res, Err(Error::NotAShareHolder)));
+
+        let mut decryptor = Decryptor::new(combiner.clone());
+        decryptor = decryptor.share_file(&mut &share_files[2][..]).unwrap();
+        assert!(matches!(decryptor.verify(&mut &encrypted[..]), Err(Error::NotEnoughShares(1, 2))));
+        decryptor = decryptor.share_file(&mut &share_files[0][..]).unwrap();
+        let mut decrypted = Vec::new();
+        decryptor.decrypt(&mut &encrypted[..], &mut decrypted).unwrap();
+        assert_eq!(decrypted, b"split custody");
+
+        // A share file can be mixed with a share held directly, but only the combiner can read it.
+        let decryptor = Decryptor::new(officers[1].0.clone()).key(combiner.clone()).share_file(&mut &share_files[0][..]).unwrap();
+        assert!(decryptor.verify(&mut &encrypted[..]).is_ok());
+        assert!(matches!(Decryptor::new(officers[1].0.clone()).share_file(&mut &share_files[0][..]), Err(Error::BadKey)));
+
+        // Shares of another file don't count.
+        let mut other = Vec::new();
+        Encryptor::new().threshold(2, officers.iter().map(|(_, p)| *p)).encrypt(&mut &b"other"[..], &mut other).unwrap();
+        let decryptor = Decryptor::new(officers[1].0.clone()).key(combiner).share_file(&mut &share_files[0][..]).unwrap();
+        assert!(matches!(decryptor.verify(&mut &other[..]), Err(Error::NotEnoughShares(1, 2))));
+    }
+
+    #[test]
+    fn hybrid_recipients() {
+        for &hints in &[false, true] {
+            let (classical, classical_public) = generate_random_keys(1).remove(0);
+            let hybrid = [HybridSecretKey::new().unwrap(), HybridSecretKey::new().unwrap()];
+            let mut encrypted = Vec::new();
+            let encryptor = Encryptor::new()
+                .recipient(classical_public)
+                .recipients(hybrid.iter().map(HybridSecretKey::get_public))
+                .recipient(hybrid[0].get_public())
+                .recipient_hints(hints);
+            encryptor.encrypt(&mut &b"for decades"[..], &mut encrypted).unwrap();
+            let header = Header::read(&mut &encrypted[..]).unwrap();
+            let kinds: Vec<_> = header.stanzas.iter().map(|s| s.kind).collect();
+            assert_eq!(kinds, [header::STANZA_X25519, header::STANZA_X25519_HYBRID, header::STANZA_X25519_HYBRID]);
+
+            for key in &hybrid {
+                let mut decrypted = Vec::new();
+                Decryptor::with_identity(key.clone()).decrypt(&mut &encrypted[..], &mut decrypted).unwrap();
+                assert_eq!(decrypted, b"for decades");
+            }
+            assert!(Decryptor::new(classical).verify(&mut &encrypted[..]).is_ok());
+            let other = Decryptor::with_identity(HybridSecretKey::new().unwrap());
+            assert!(matches!(other.verify(&mut &encrypted[..]), Err(Error::BadKey)));
+            // The X25519 half of a hybrid key can't decrypt on its own.
+            assert!(matches!(Decryptor::new(hybrid[1].x25519()).verify(&mut &encrypted[..]), Err(Error::BadKey)));
+        }
+    }
+
+    /// A recipient kind the core knows nothing about: the file key encrypted under a pre-shared key.
+    #[derive(Clone)]
+    struct PreShared([u8; 32]);
+
+    const STANZA_PRE_SHARED: u8 = 0x80;
+
+    impl PreShared {
+        fn hint(&self) -> [u8; HINT_LEN] {
+            crate::recipient_hint(&Secret::from_vec(self.0.to_vec()))
+        }
+    }
+
+    impl Recipient for PreShared {
+        fn wrap_file_key(&self, file_key: &Secret, ctx: &WrapContext) -> Result<Stanza, Error> {
+            let mut body = if ctx.hints() { self.hint().to_vec() } else { Vec::new() };
+            body.extend(encryption::encrypt_data(&self.0, file_key.clone().into_vec(), ctx.scheme())?);
+            Ok(Stanza::new(STANZA_PRE_SHARED, body))
+        }
+    }
+
+    impl Identity for PreShared {
+        fn unwrap_file_key(&self, ctx: &UnwrapContext) -> Result<Option<Secret>, Error> {
+            let scheme = ctx.scheme();
+            ctx.unwrap_stanzas(STANZA_PRE_SHARED, &self.hint(), |body| {
+                encryption::decrypt_data(&self.0, body.to_vec(), scheme).ok().map(Secret::from_vec)
+            })
+        }
+    }
+
+    #[test]
+    fn pluggable_recipients() {
+        let (key, public) = generate_random_keys(1).remove(0);
+        let mut encrypted = Vec::new();
+        Encryptor::new().recipient(PreShared([4u8; 32])).recipient(public).encrypt(&mut &b"plugged in"[..], &mut encrypted).unwrap();
+
+        let mut decrypted = Vec::new();
+        decrypt_file_with_keys(&mut &encrypted[..], PreShared([4u8; 32]), &mut decrypted).unwrap();
+        assert_eq!(decrypted, b"plugged in");
+        // Other identities skip the stanza they don't know.
+        assert!(Decryptor::new(key).verify(&mut &encrypted[..]).is_ok());
+        assert!(matches!(Decryptor::with_identity(PreShared([5u8; 32])).verify(&mut &encrypted[..]), Err(Error::BadKey)));
+
+        let mut encrypted = Vec::new();
+        encrypt_file_with_keys(&mut &b"plugged in"[..], &[PreShared([4u8; 32])], &mut encrypted, Scheme::Chacha20Poly1305).unwrap();
+        assert!(Decryptor::with_identity(PreShared([4u8; 32])).verify(&mut &encrypted[..]).is_ok());
+
+        // Hints work the same for them, through the public helpers.
+        let mut encrypted = Vec::new();
+        let encryptor = Encryptor::new().recipient(PreShared([4u8; 32])).recipient(PreShared([5u8; 32])).recipient_hints(true);
+        encryptor.encrypt(&mut &b"plugged in"[..], &mut encrypted).unwrap();
+        let header = Header::read(&mut &encrypted[..]).unwrap();
+        assert!(header.stanzas[1].body.starts_with(&PreShared([5u8; 32]).hint()));
+        assert!(Decryptor::with_identity(PreShared([5u8; 32])).verify(&mut &encrypted[..]).is_ok());
+        assert!(matches!(Decryptor::with_identity(PreShared([6u8; 32])).verify(&mut &encrypted[..]), Err(Error::BadKey)));
+    }
+
+    #[test]
+    fn bad_stanza_doesnt_stop_other_keys() {
+        let keys = generate_random_keys(2);
+        let hybrid = HybridSecretKey::new().unwrap();
+        let mut encrypted = Vec::new();
+        let encryptor = Encryptor::new().recipients(keys.iter().map(|(_, p)| *p)).recipient(hybrid.get_public()).recipient_hints(true);
+        encryptor.encrypt(&mut &b"plan b"[..], &mut encrypted).unwrap();
+        let mut input = &encrypted[..];
+        let mut header = Header::read(&mut input).unwrap();
+        // The sender itself can put a bad stanza in the header and still seal it.
+        let file_key = Decryptor::new(keys[1].0.clone()).find_file_key(&header).unwrap();
+        *header.stanzas[0].body.last_mut().unwrap() ^= 1;
+        header.seal(&file_key).unwrap();
+        let mut corrupted = Vec::new();
+        header.write(&mut corrupted).unwrap();
+        corrupted.extend_from_slice(input);
+
+        // The first key's stanza is bad, the second key or an identity still decrypts the file.
+        let mut decrypted = Vec::new();
+        Decryptor::new(keys[0].0.clone()).key(keys[1].0.clone()).decrypt(&mut &corrupted[..], &mut decrypted).unwrap();
+        assert_eq!(decrypted, b"plan b");
+        assert!(Decryptor::new(keys[0].0.clone()).identity(hybrid).verify(&mut &corrupted[..]).is_ok());
+        // On its own the first key reports the corruption, not a wrong key.
+        assert!(matches!(Decryptor::new(keys[0].0.clone()).verify(&mut &corrupted[..]), Err(Error::BadStanza)));
+        let stranger = generate_random_keys(1).remove(0).0;
+        assert!(matches!(Decryptor::new(keys[0].0.clone()).key(stranger).verify(&mut &corrupted[..]), Err(Error::BadStanza)));
+    }
+
+    #[test]
+    fn duplicate_recipients() {
+        let keys = generate_random_keys(2);
+        let pub_keys = [keys[0].1, keys[1].1, keys[0].1, keys[0].1];
+        let mut encrypted = Vec::new();
+        encrypt_file_with_keys(&mut &b"once is enough"[..], &pub_keys, &mut encrypted, Scheme::AES256GCM).unwrap();
+        assert_eq!(Header::read(&mut &encrypted[..]).unwrap().stanzas.len(), 2);
+        let mut decrypted = Vec::new();
+        decrypt_file_with_keys(&mut &encrypted[..], keys[0].0.clone(), &mut decrypted).unwrap();
+        assert_eq!(decrypted, b"once is enough");
+    }
+
+    #[test]
+    fn no_recipients() {
+        let res = Encryptor::new().encrypt(&mut &b""[..], &mut Vec::new());
+        assert!(matches!(res, Err(Error::NoRecipients)));
+    }
+
+    #[test]
+    fn wrapped_reader_and_writer() {
+        let keys = generate_random_keys(2);
+        let mut writer = Encryptor::new().recipient(keys[0].1).wrap_output(Vec::new()).unwrap();
+        writer.write_all(b"written ").unwrap();
+        writer.write_all(b"in parts").unwrap();
+        let encrypted = writer.finish().unwrap();
+
+        let mut reader = Decryptor::new(keys[0].0.clone()).wrap_input(&encrypted[..]).unwrap();
+        let mut decrypted = String::new();
+        reader.read_to_string(&mut decrypted).unwrap();
+        assert_eq!(decrypted, "written in parts");
+
+        assert!(matches!(Decryptor::new(keys[1].0.clone()).wrap_input(&encrypted[..]), Err(Error::BadKey)));
+    }
 }
@@ -1,23 +1,18 @@
-#![allow(dead_code)]
-
 mod cli;
-mod encryption;
-mod error;
-mod logic;
-mod secret;
-mod x25519;
-
-use crate::encryption::Scheme;
-use crate::x25519::UserSecretKey;
-use cli::Options;
-pub use error::Error;
-pub use secret::Secret;
-use std::fs::File;
+
+use cli::{GroupCommand, HexKeyKind, KeyCommand, KeySource, Options, PublicKeyArg, SecretKeyArg};
+use lorenz::{
+    Decryptor, Encryptor, Error, FileInfo, HybridSecretKey, KeyKind, Keyring, Recipient, UserPublicKey, UserSecretKey, UserSigningKey,
+    UserVerifyingKey,
+};
+use rustc_hex::ToHex;
+use serde_json::json;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
-use std::str::FromStr;
 use structopt::clap::{Error as ClapError, ErrorKind as ClapErrorKind};
 use structopt::StructOpt;
-use x25519_dalek::PublicKey;
+use zeroize::Zeroizing;
 
 fn main() {
     let opt = Options::from_args();
@@ -28,165 +23,512 @@ fn main() {
 
 fn handle_cli(opt: Options) -> Result<(), ClapError> {
     match opt {
-        Options::GenerateKeys { amount } => {
+        Options::GenerateKeys { amount, signing: true, .. } => {
             for i in 1..=amount {
-                let key = UserSecretKey::new()?;
+                let key = UserSigningKey::new().map_err(lorenz_error)?;
                 println!("key {}: \n{}", i, key);
             }
         }
-        Options::Encrypt { public_keys, file, mode } => {
-            let mut input = File::open(&file)?;
-            let mut output = File::create(add_lorenz_extenstion(&file))?;
-            let scheme = Scheme::from_str(&mode)?;
-            let public_keys = public_keys.into_iter().map(PublicKey::from).collect();
-            logic::encrypt_file_with_keys(&mut input, public_keys, &mut output, scheme)?;
+        Options::GenerateKeys { amount, hybrid: true, .. } => {
+            for i in 1..=amount {
+                let key = HybridSecretKey::new().map_err(lorenz_error)?;
+                println!("key {}: \n{}", i, key);
+            }
+        }
+        Options::GenerateKeys { amount, out: Some(out), mnemonic, .. } => {
+            if amount != 1 {
+                return Err(ClapError::with_description("--out can only be used with a single key", ClapErrorKind::InvalidValue));
+            }
+            let key = if mnemonic {
+                let (key, words) = UserSecretKey::new_with_mnemonic().map_err(lorenz_error)?;
+                println!("mnemonic: {}", *words);
+                key
+            } else {
+                UserSecretKey::new().map_err(lorenz_error)?
+            };
+            let passphrase = read_passphrase(true)?;
+            let mut file = create_private_file(&out)?;
+            key.write_key_file(&passphrase, &mut file).map_err(lorenz_error)?;
+            println!("publicKey: {}", key.get_public());
+            println!("fingerprint: {}", key.get_public().fingerprint());
+        }
+        Options::GenerateKeys { amount, out: None, mnemonic: true, .. } => {
+            for i in 1..=amount {
+                let (key, words) = UserSecretKey::new_with_mnemonic().map_err(lorenz_error)?;
+                println!("key {}: \nmnemonic: {}\n{}", i, *words, key);
+            }
+        }
+        Options::GenerateKeys { amount, out: None, mnemonic: false, .. } => {
+            for i in 1..=amount {
+                let key = UserSecretKey::new().map_err(lorenz_error)?;
+                println!("key {}: \n{}", i, key);
+            }
+        }
+        Options::Encrypt { recipients, share_holders, threshold, passphrase, hints, sign_with, sender, file, mode, output } => {
+            let mut hybrid_keys = Vec::new();
+            let mut public_keys: Vec<UserPublicKey> = Vec::new();
+            let mut holders = Vec::new();
+            if !recipients.is_empty() || !share_holders.is_empty() {
+                let keyring = keyring()?;
+                for key in resolve_recipients(&keyring, &recipients)? {
+                    match key {
+                        PublicKeyArg::X25519(key) => public_keys.push(key),
+                        PublicKeyArg::Hybrid(key) => hybrid_keys.push(key),
+                    }
+                }
+                for name in &share_holders {
+                    holders.extend(keyring.resolve(name).map_err(lorenz_error)?);
+                }
+            }
+            let output = output.unwrap_or_else(|| if is_stdio(&file) { file.clone() } else { add_lorenz_extenstion(&file) });
+            let mut input = open_input(&file)?;
+            let mut encryptor = Encryptor::new().recipients(public_keys).recipients(hybrid_keys).recipient_hints(hints).scheme(mode);
+            if passphrase {
+                encryptor = encryptor.passphrase(&read_passphrase(true)?);
+            }
+            if let Some(key) = sign_with {
+                encryptor = encryptor.sign_with(key);
+            }
+            if let Some(key) = sender {
+                encryptor = encryptor.authenticate_as(key);
+            }
+            if let Some(threshold) = threshold {
+                encryptor = encryptor.threshold(threshold, holders);
+            }
+            write_output(&output, None, |mut output| encryptor.encrypt(&mut input, &mut output))?;
+        }
+        Options::Decrypt { key, expect_signer, sender, file, shares, output } => {
+            let mut decryptor = decryptor(key, expect_signer, sender)?;
+            for share_file in shares {
+                decryptor = decryptor.share_file(&mut File::open(share_file)?).map_err(lorenz_error)?;
+            }
+            let output = match output {
+                Some(output) => output,
+                None if is_stdio(&file) => file.clone(),
+                None => remove_lorenz_extenstion(&file)?,
+            };
+            // The file key is found before the output is touched, so a wrong key doesn't clobber an existing file.
+            let mut reader = decryptor.wrap_input(open_input(&file)?).map_err(lorenz_error)?;
+            write_output(&output, None, |output| {
+                io::copy(&mut reader, output)?;
+                Ok(())
+            })?;
         }
-        Options::Decrypt { private_key, file, mode } => {
-            let mut input = File::open(&file)?;
-            let output_path = remove_lorenz_extenstion(file)?;
-            let mut output = File::create(&output_path)?;
-            let scheme = Scheme::from_str(&mode)?;
-            logic::decrypt_file_with_keys(&mut input, private_key, &mut output, scheme)?;
+        Options::Verify { key, expect_signer, sender, file } => {
+            let decryptor = decryptor(key, expect_signer, sender)?;
+            let len = decryptor.verify(&mut open_input(&file)?).map_err(lorenz_error)?;
+            println!("OK: {} is intact and decrypts to {} bytes", file.display(), len);
+        }
+        Options::Combine { file, output } => {
+            if is_stdio(&file) {
+                return Err(ClapError::with_description("combine can't read the file from stdin", ClapErrorKind::InvalidValue));
+            }
+            let output = match output {
+                Some(output) => output,
+                None => remove_lorenz_extenstion(&file)?,
+            };
+            let mut decryptor: Option<Decryptor> = None;
+            loop {
+                let key = Zeroizing::new(rpassword::prompt_password("Private key of a share holder: ")?);
+                let key: UserSecretKey = key.parse().map_err(lorenz_error)?;
+                let with_key = match decryptor.take() {
+                    Some(decryptor) => decryptor.key(key),
+                    None => Decryptor::new(key),
+                };
+                let (shares, threshold) = with_key.shares(&mut open_input(&file)?).map_err(lorenz_error)?;
+                if threshold == 0 {
+                    return Err(ClapError::with_description(
+                        "The file isn't split between share holders",
+                        ClapErrorKind::InvalidValue,
+                    ));
+                }
+                eprintln!("{} of {} shares collected", shares, threshold);
+                if shares >= usize::from(threshold) {
+                    let mut reader = with_key.wrap_input(open_input(&file)?).map_err(lorenz_error)?;
+                    write_output(&output, None, |output| {
+                        io::copy(&mut reader, output)?;
+                        Ok(())
+                    })?;
+                    break;
+                }
+                decryptor = Some(with_key);
+            }
+        }
+        Options::UnwrapShare { key, file, combiner, output } => {
+            let decryptor = decryptor(key, None, None)?;
+            let combiner = match keyring()?.resolve(&combiner).map_err(lorenz_error)?.as_slice() {
+                [combiner] => *combiner,
+                _ => {
+                    return Err(ClapError::with_description(
+                        "The shares can only be sent to a single key",
+                        ClapErrorKind::InvalidValue,
+                    ))
+                }
+            };
+            let output = output.unwrap_or_else(|| if is_stdio(&file) { file.clone() } else { add_share_extension(&file) });
+            let mut input = open_input(&file)?;
+            let mut shares = 0;
+            write_output(&output, None, |mut output| {
+                shares = decryptor.unwrap_shares(&mut input, combiner, &mut output)?;
+                Ok(())
+            })?;
+            eprintln!("Unwrapped {} share(s) for {}", shares, combiner.fingerprint());
+        }
+        Options::AddRecipient { key, recipients, file, output } => {
+            let decryptor = decryptor(key, None, None)?;
+            let recipients: Vec<Box<dyn Recipient>> = resolve_recipients(&keyring()?, &recipients)?
+                .into_iter()
+                .map(|key| match key {
+                    PublicKeyArg::X25519(key) => Box::new(key) as Box<dyn Recipient>,
+                    PublicKeyArg::Hybrid(key) => Box::new(key),
+                })
+                .collect();
+            rewrite_file(&file, output, |mut input, mut output| decryptor.add_recipients(&recipients, &mut input, &mut output))?;
+        }
+        Options::RemoveRecipient { key, stanzas, file, output } => {
+            let decryptor = decryptor(key, None, None)?;
+            rewrite_file(&file, output, |mut input, mut output| decryptor.remove_stanzas(&stanzas, &mut input, &mut output))?;
+        }
+        Options::Inspect { json, file } => {
+            let info = lorenz::inspect(&mut open_input(&file)?).map_err(lorenz_error)?;
+            if json {
+                println!("{}", inspect_json(&info));
+            } else {
+                print_inspect(&info);
+            }
         }
+        Options::Key { command } => handle_key_command(command)?,
+        Options::Group { command } => handle_group_command(command)?,
     };
 
     Ok(())
 }
 
-fn add_lorenz_extenstion<P: AsRef<Path>>(path: P) -> PathBuf {
-    let mut path = path.as_ref().as_os_str().to_owned();
-    path.push(".lorenz");
-    path.into()
-}
-
-fn remove_lorenz_extenstion<P: AsRef<Path>>(path: P) -> Result<PathBuf, ClapError> {
-    let err = ClapError::with_description("Bad File, doesn't end with `lorenz` extension", ClapErrorKind::InvalidValue);
-    let path = path.as_ref();
-    if let Some(ext) = path.extension() {
-        if ext == "lorenz" {
-            let mut res = path.to_owned();
-            let file_without_path = path.file_stem().ok_or(err)?;
-            res.set_file_name(file_without_path);
-            return Ok(res);
+fn decryptor(key: KeySource, expect_signer: Option<UserVerifyingKey>, sender: Option<UserPublicKey>) -> Result<Decryptor, ClapError> {
+    let mut decryptor = match key {
+        KeySource { key: Some(key), .. } => {
+            let key = Zeroizing::new(key);
+            // Keys are told apart from identity names by their prefix.
+            if key.get(..7).is_some_and(|prefix| prefix.eq_ignore_ascii_case("lorenz-")) {
+                with_secret_key(key.parse().map_err(lorenz_error)?)
+            } else {
+                Decryptor::new(keyring()?.identity(&key).map_err(lorenz_error)?)
+            }
+        }
+        KeySource { key_file: Some(key_file), .. } => {
+            let passphrase = read_passphrase(false)?;
+            Decryptor::new(UserSecretKey::read_key_file(&mut File::open(key_file)?, &passphrase).map_err(lorenz_error)?)
         }
+        KeySource { ssh_key: Some(ssh_key), .. } => Decryptor::new(read_ssh_key(&ssh_key)?),
+        KeySource { passphrase: true, .. } => Decryptor::with_passphrase(&read_passphrase(false)?),
+        KeySource { .. } => {
+            let mut identities = keyring()?.identities().map_err(lorenz_error)?.into_iter();
+            let first = identities.next().ok_or_else(|| {
+                ClapError::with_description(
+                    "No private key was given and the keyring has no identities",
+                    ClapErrorKind::MissingRequiredArgument,
+                )
+            })?;
+            identities.fold(Decryptor::new(first), Decryptor::key)
+        }
+    };
+    if let Some(signer) = expect_signer {
+        decryptor = decryptor.expect_signer(signer);
     }
-    Err(err)
+    if let Some(sender) = sender {
+        decryptor = decryptor.expect_sender(sender);
+    }
+    Ok(decryptor)
 }
 
-#[cfg(test)]
-mod tests {
-    use rand::{thread_rng, RngCore};
-    use std::fs::File;
-    use std::io::{self, Read, Seek, SeekFrom, Write};
-
-    use super::*;
-    use crate::encryption::Scheme;
-    use crate::x25519::UserSecretKey;
-    use tempfile::tempfile;
-
-    #[test]
-    fn full_test() {
-        let mut input = get_rand_file();
-        let mut encrypted = tempfile().unwrap();
-        let mut decrypted = tempfile().unwrap();
+fn with_secret_key(key: SecretKeyArg) -> Decryptor {
+    match key {
+        SecretKeyArg::X25519(key) => Decryptor::new(key),
+        SecretKeyArg::Hybrid(key) => Decryptor::with_identity(key),
+    }
+}
 
-        let mut keys = encrypt(6, &mut input, &mut encrypted);
+/// Reads an OpenSSH private key, prompting for its passphrase only if it's encrypted.
+fn read_ssh_key(path: &Path) -> Result<UserSecretKey, ClapError> {
+    let pem = Zeroizing::new(fs::read_to_string(path)?);
+    match UserSecretKey::from_ssh_key(&pem, None) {
+        Err(Error::SshKeyEncrypted) => {
+            let passphrase = Zeroizing::new(rpassword::prompt_password("SSH key passphrase: ")?);
+            UserSecretKey::from_ssh_key(&pem, Some(&passphrase)).map_err(lorenz_error)
+        }
+        key => key.map_err(lorenz_error),
+    }
+}
 
-        decrypt(keys.remove(4), &mut encrypted, &mut decrypted);
+/// Resolves the `-r` arguments, the keyring only holds regular keys so hybrid ones are always given in full.
+fn resolve_recipients(keyring: &Keyring, names: &[String]) -> Result<Vec<PublicKeyArg>, ClapError> {
+    let mut keys = Vec::new();
+    for name in names {
+        match name.parse() {
+            Ok(PublicKeyArg::Hybrid(key)) => keys.push(PublicKeyArg::Hybrid(key)),
+            _ => keys.extend(keyring.resolve(name).map_err(lorenz_error)?.into_iter().map(PublicKeyArg::X25519)),
+        }
+    }
+    Ok(keys)
+}
 
-        let mut before = Vec::new();
-        let mut after = Vec::new();
+fn keyring() -> Result<Keyring, ClapError> {
+    let dir = Keyring::default_dir().ok_or_else(|| {
+        ClapError::with_description("Can't find the keyring directory, set $LORENZ_HOME", ClapErrorKind::InvalidValue)
+    })?;
+    Ok(Keyring::new(dir))
+}
 
-        input.read_to_end(&mut before).unwrap();
-        decrypted.read_to_end(&mut after).unwrap();
-        assert_eq!(before, after);
+fn handle_key_command(command: KeyCommand) -> Result<(), ClapError> {
+    let keyring = keyring()?;
+    match command {
+        KeyCommand::Import { identity: false, name, key } => {
+            let key: UserPublicKey = key.parse().map_err(lorenz_error)?;
+            keyring.add_contact(&name, &key).map_err(lorenz_error)?;
+        }
+        KeyCommand::Import { identity: true, name, key } => {
+            let key = Zeroizing::new(key);
+            let key: UserSecretKey = key.parse().map_err(lorenz_error)?;
+            keyring.add_identity(&name, &key).map_err(lorenz_error)?;
+            println!("publicKey: {}", key.get_public());
+        }
+        KeyCommand::Generate { name } => {
+            let key = UserSecretKey::new().map_err(lorenz_error)?;
+            keyring.add_identity(&name, &key).map_err(lorenz_error)?;
+            println!("publicKey: {}", key.get_public());
+        }
+        KeyCommand::Recover { name } => {
+            let words = Zeroizing::new(rpassword::prompt_password("Mnemonic: ")?);
+            let key = UserSecretKey::from_mnemonic(&words).map_err(lorenz_error)?;
+            match name {
+                Some(name) => {
+                    keyring.add_identity(&name, &key).map_err(lorenz_error)?;
+                    println!("publicKey: {}", key.get_public());
+                }
+                None => print!("{}", key),
+            }
+        }
+        KeyCommand::Derive { from, mnemonic, name, label } => {
+            let master = if mnemonic {
+                let words = Zeroizing::new(rpassword::prompt_password("Mnemonic: ")?);
+                UserSecretKey::from_mnemonic(&words).map_err(lorenz_error)?
+            } else {
+                keyring.identity(from.as_deref().expect("--from is required without --mnemonic")).map_err(lorenz_error)?
+            };
+            let key = master.derive(&label).map_err(lorenz_error)?;
+            match name {
+                Some(name) => {
+                    keyring.add_identity(&name, &key).map_err(lorenz_error)?;
+                    println!("publicKey: {}", key.get_public());
+                }
+                None => print!("{}", key),
+            }
+        }
+        KeyCommand::List => {
+            for &(kind, title) in &[(KeyKind::Identity, "identities"), (KeyKind::Contact, "contacts")] {
+                println!("{}:", title);
+                for name in keyring.names(kind).map_err(lorenz_error)? {
+                    let key = keyring.public_key(&name).map_err(lorenz_error)?;
+                    println!("  {}: {}\n    fingerprint: {}", name, key, key.fingerprint());
+                }
+            }
+        }
+        KeyCommand::Export { name } => println!("{}", keyring.public_key(&name).map_err(lorenz_error)?),
+        KeyCommand::Remove { name } => keyring.remove(&name).map_err(lorenz_error)?,
+        KeyCommand::FromHex { kind, key } => {
+            let key = Zeroizing::new(key);
+            match kind {
+                HexKeyKind::Public => println!("{}", UserPublicKey::from_hex(&key).map_err(lorenz_error)?),
+                HexKeyKind::Private => print!("{}", UserSecretKey::from_hex(&key).map_err(lorenz_error)?),
+                HexKeyKind::Signing => print!("{}", UserSigningKey::from_hex(&key).map_err(lorenz_error)?),
+                HexKeyKind::Verifying => println!("{}", UserVerifyingKey::from_hex(&key).map_err(lorenz_error)?),
+            }
+        }
     }
+    Ok(())
+}
 
-    pub fn get_rand_file() -> File {
-        let mut input = vec![0u8; 1986];
-        let mut rng = thread_rng();
-        rng.fill_bytes(&mut input);
-        let mut f = tempfile().unwrap();
-        f.write_all(&input).unwrap();
-        f.seek(SeekFrom::Start(0)).unwrap();
-        f
+fn handle_group_command(command: GroupCommand) -> Result<(), ClapError> {
+    let keyring = keyring()?;
+    match command {
+        GroupCommand::Set { name, members } => keyring.set_group(name.trim_start_matches('@'), &members).map_err(lorenz_error)?,
+        GroupCommand::List => {
+            for name in keyring.group_names().map_err(lorenz_error)? {
+                println!("@{}: {}", name, keyring.group(&name).map_err(lorenz_error)?.join(" "));
+            }
+        }
+        GroupCommand::Remove { name } => keyring.remove_group(name.trim_start_matches('@')).map_err(lorenz_error)?,
     }
+    Ok(())
+}
 
-    fn take<R: Read>(reader: &mut R) -> io::Result<u8> {
-        let mut b = [0];
-        reader.read_exact(&mut b)?;
-        Ok(b[0])
+fn lorenz_error(err: Error) -> ClapError {
+    match err {
+        Error::IoError(e) => e.into(),
+        Error::RngError(e) => ClapError::with_description(&e.to_string(), ClapErrorKind::Io),
+        e => ClapError::with_description(&e.to_string(), ClapErrorKind::InvalidValue),
     }
+}
 
-    fn decrypt(key: UserSecretKey, mut encrypted: &mut File, output: &mut File) {
-        let mut pubkey = [0u8; 32];
-        encrypted.read_exact(&mut pubkey).unwrap();
-        let amount = take(&mut encrypted).unwrap();
-
-        let shared = key.derive_secret(&pubkey.into(), 32);
-        let (aes_key, left) = find_aes_key(&mut encrypted, shared, amount);
-        let aes_key = aes_key.unwrap();
-        encrypted.seek(SeekFrom::Current(left as i64 * 60)).unwrap();
-
-        let file_size = encrypted.metadata().unwrap().len() as usize - 32 - 1 - (60 * amount as usize);
-
-        let mut file_data = Vec::with_capacity(file_size);
-        encrypted.read_to_end(&mut file_data).unwrap();
+fn print_inspect(info: &FileInfo) {
+    println!("version: {}", info.version);
+    println!("scheme: {:?}", info.scheme);
+    println!("ephemeral key: 0x{}", info.ephemeral.to_hex::<String>());
+    println!("recipient hints: {}", if info.recipient_hints { "yes" } else { "no" });
+    println!("signed: {}", if info.signed { "yes" } else { "no" });
+    println!("recipients: {}", info.stanzas.len());
+    for (i, stanza) in info.stanzas.iter().enumerate() {
+        print!("  {}: {}, {} bytes", i, stanza.kind_name(), stanza.len);
+        if stanza.added {
+            print!(" (added)");
+        }
+        if let Some(hint) = stanza.hint {
+            print!(", hint 0x{}", hint.to_hex::<String>());
+        }
+        if let Some(work_factor) = stanza.work_factor {
+            print!(", work factor {}", work_factor);
+        }
+        if let Some(threshold) = stanza.threshold {
+            print!(", {} shares needed", threshold);
+        }
+        println!();
+    }
+    println!("header: {} bytes", info.header_len);
+    match info.plaintext_len() {
+        Some(len) => println!("payload: {} bytes ({} bytes decrypted)", info.payload_len, len),
+        None => println!("payload: {} bytes (truncated or corrupted)", info.payload_len),
+    }
+}
 
-        let original = encryption::decrypt_data(aes_key.as_ref(), file_data, Scheme::AES256GCM).unwrap();
-        output.write_all(&original).unwrap();
+fn inspect_json(info: &FileInfo) -> serde_json::Value {
+    let stanzas: Vec<_> = info
+        .stanzas
+        .iter()
+        .map(|stanza| {
+            json!({
+                "kind": stanza.kind_name(),
+                "kind_id": stanza.kind,
+                "length": stanza.len,
+                "added": stanza.added,
+                "hint": stanza.hint.map(|hint| format!("0x{}", hint.to_hex::<String>())),
+                "work_factor": stanza.work_factor,
+                "threshold": stanza.threshold,
+            })
+        })
+        .collect();
+    json!({
+        "version": info.version,
+        "scheme": format!("{:?}", info.scheme),
+        "ephemeral_key": format!("0x{}", info.ephemeral.to_hex::<String>()),
+        "recipient_hints": info.recipient_hints,
+        "signed": info.signed,
+        "recipients": info.stanzas.len(),
+        "stanzas": stanzas,
+        "header_length": info.header_len,
+        "payload_length": info.payload_len,
+        "plaintext_length": info.plaintext_len(),
+    })
+}
 
-        encrypted.seek(SeekFrom::Start(0)).unwrap();
-        output.seek(SeekFrom::Start(0)).unwrap();
+fn read_passphrase(confirm: bool) -> Result<Zeroizing<String>, ClapError> {
+    let passphrase = Zeroizing::new(rpassword::prompt_password("Passphrase: ")?);
+    if passphrase.is_empty() {
+        return Err(ClapError::with_description("The passphrase can't be empty", ClapErrorKind::InvalidValue));
     }
-
-    fn find_aes_key<R: Read>(f: &mut R, shared: Secret, amount: u8) -> (Option<Secret>, u8) {
-        for i in 1..=amount {
-            let mut encrypted_key = vec![0u8; 60];
-            match f.read_exact(&mut encrypted_key).ok() {
-                None => return (None, amount - i),
-                Some(()) => (),
-            };
-            match encryption::decrypt_data(shared.as_ref(), encrypted_key, Scheme::AES256GCM) {
-                Err(_) => continue,
-                Ok(key) => return (Some(Secret::from_vec(key)), amount - i),
-            }
+    if confirm {
+        let again = Zeroizing::new(rpassword::prompt_password("Confirm passphrase: ")?);
+        if *again != *passphrase {
+            return Err(ClapError::with_description("The passphrases don't match", ClapErrorKind::InvalidValue));
         }
-        (None, 0)
     }
+    Ok(passphrase)
+}
 
-    fn encrypt(keys: u8, input_file: &mut File, output: &mut File) -> Vec<UserSecretKey> {
-        let mut input = Vec::with_capacity(1987);
-        input_file.read_to_end(&mut input).unwrap();
-        let aes = Secret::generate32().unwrap();
-        let ephemeral = x25519::EphemeralKey::new().unwrap();
-
-        output.write_all(ephemeral.get_public().as_bytes()).unwrap();
+/// Creates a new file readable only by the current user, fails if the file already exists.
+fn create_private_file(path: &Path) -> Result<File, ClapError> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    Ok(options.open(path)?)
+}
 
-        output.write_all(&[keys]).unwrap();
+/// Runs `rewrite` from `file` into `output`.
+/// Without an output the result goes into a temporary file which then replaces `file`.
+fn rewrite_file<F>(file: &Path, output: Option<PathBuf>, rewrite: F) -> Result<(), ClapError>
+where
+    F: FnOnce(&mut dyn Read, &mut dyn Write) -> Result<(), Error>,
+{
+    let mut input = open_input(file)?;
+    match output {
+        Some(output) => write_output(&output, None, |output| rewrite(&mut input, output)),
+        None if is_stdio(file) => rewrite(&mut input, &mut io::stdout()).map_err(lorenz_error),
+        None => write_output(file, Some(fs::metadata(file)?.permissions()), |output| rewrite(&mut input, output)),
+    }
+}
 
-        let keys = generate_random_keys(keys);
-        for (_, public) in &keys {
-            let shared = ephemeral.derive_secret(public, 32);
-            let enc_key = encryption::encrypt_data(shared.as_ref(), aes.clone().into_vec(), Scheme::AES256GCM).unwrap();
-            output.write_all(&enc_key).unwrap();
-        }
+/// Runs `write` into a temporary file next to `path`, which only replaces `path` once `write` succeeded,
+/// so a failure midway never leaves a truncated or partially written file behind. `-` writes straight to stdout.
+/// The temporary file has a unique name and is only readable by its owner, unless `permissions` are given.
+fn write_output<F>(path: &Path, permissions: Option<fs::Permissions>, write: F) -> Result<(), ClapError>
+where
+    F: FnOnce(&mut dyn Write) -> Result<(), Error>,
+{
+    if is_stdio(path) {
+        return write(&mut io::stdout()).map_err(lorenz_error);
+    }
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    // Dropping it before it's persisted deletes it.
+    let mut tmp = tempfile::Builder::new().prefix(".lorenz-").suffix(".tmp").tempfile_in(dir)?;
+    if let Some(permissions) = permissions {
+        tmp.as_file().set_permissions(permissions)?;
+    }
+    write(tmp.as_file_mut()).map_err(lorenz_error)?;
+    tmp.as_file().sync_all()?;
+    tmp.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
 
-        let enc_file = encryption::encrypt_data(aes.as_ref(), input, Scheme::AES256GCM).unwrap();
-        output.write_all(&enc_file).unwrap();
-        output.seek(SeekFrom::Start(0)).unwrap();
-        input_file.seek(SeekFrom::Start(0)).unwrap();
+fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
 
-        keys.into_iter().map(|(key, _)| key).collect()
+fn open_input(path: &Path) -> Result<Box<dyn Read>, ClapError> {
+    if is_stdio(path) {
+        Ok(Box::new(io::stdin()))
+    } else {
+        Ok(Box::new(File::open(path)?))
     }
+}
 
-    pub fn generate_random_keys(amount: u8) -> Vec<(UserSecretKey, PublicKey)> {
-        let mut res = Vec::with_capacity(amount as usize);
-        for _ in 0..amount {
-            let key = UserSecretKey::new().unwrap();
-            let public = key.get_public();
-            res.push((key, public));
+fn add_lorenz_extenstion<P: AsRef<Path>>(path: P) -> PathBuf {
+    let mut path = path.as_ref().as_os_str().to_owned();
+    path.push(".lorenz");
+    path.into()
+}
+
+fn add_share_extension<P: AsRef<Path>>(path: P) -> PathBuf {
+    let mut path = path.as_ref().as_os_str().to_owned();
+    path.push(".share");
+    path.into()
+}
+
+fn remove_lorenz_extenstion<P: AsRef<Path>>(path: P) -> Result<PathBuf, ClapError> {
+    let err = ClapError::with_description("Bad File, doesn't end with `lorenz` extension", ClapErrorKind::InvalidValue);
+    let path = path.as_ref();
+    if let Some(ext) = path.extension() {
+        if ext == "lorenz" {
+            let mut res = path.to_owned();
+            let file_without_path = path.file_stem().ok_or(err)?;
+            res.set_file_name(file_without_path);
+            return Ok(res);
         }
-        res
     }
+    Err(err)
 }
@@ -0,0 +1,64 @@
+use crate::x25519::{UserSecretKey, SALT};
+use crate::Error;
+use bip39::{Language, Mnemonic};
+use ring::hkdf;
+use ring::rand::{SecureRandom, SystemRandom};
+use zeroize::Zeroizing;
+
+const MNEMONIC_INFO: &[u8] = b"lorenz-mnemonic-x25519";
+/// 256 bits of entropy, written as 24 words.
+const ENTROPY_LEN: usize = 32;
+pub const MNEMONIC_WORDS: usize = 24;
+
+/// The same words always derive the same key.
+fn derive_key(entropy: &[u8]) -> UserSecretKey {
+    let mut secret = Zeroizing::new([0u8; 32]);
+    hkdf::extract_and_expand(&SALT, entropy, MNEMONIC_INFO, &mut secret[..]);
+    UserSecretKey::from_bytes(&secret)
+}
+
+impl UserSecretKey {
+    /// A new key, and the BIP39 words it's derived from, which are enough to recover it with `from_mnemonic`.
+    pub fn new_with_mnemonic() -> Result<(Self, Zeroizing<String>), Error> {
+        let mut entropy = Zeroizing::new([0u8; ENTROPY_LEN]);
+        SystemRandom::new().fill(&mut entropy[..])?;
+        let mnemonic = Mnemonic::from_entropy(&entropy[..]).expect("the entropy has a valid length");
+        Ok((derive_key(&entropy[..]), Zeroizing::new(mnemonic.to_string())))
+    }
+
+    /// Recovers the key of `new_with_mnemonic` from its words.
+    pub fn from_mnemonic(words: &str) -> Result<Self, Error> {
+        let words = Zeroizing::new(words.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase());
+        let mnemonic = Mnemonic::parse_in(Language::English, &words[..]).map_err(|e| Error::BadMnemonic(e.to_string()))?;
+        if mnemonic.word_count() != MNEMONIC_WORDS {
+            return Err(Error::BadMnemonic(format!("expected {} words, got {}", MNEMONIC_WORDS, mnemonic.word_count())));
+        }
+        let entropy = Zeroizing::new(mnemonic.to_entropy());
+        Ok(derive_key(&entropy))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mnemonic_recovery() {
+        let (key, words) = UserSecretKey::new_with_mnemonic().unwrap();
+        assert_eq!(words.split(' ').count(), MNEMONIC_WORDS);
+        let recovered = UserSecretKey::from_mnemonic(&format!("  {}\n", words.to_uppercase())).unwrap();
+        assert_eq!(recovered.get_public(), key.get_public());
+
+        // The derivation must never change, or backed up words would recover a different key.
+        let zero = format!("{} art", vec!["abandon"; MNEMONIC_WORDS - 1].join(" "));
+        assert_eq!(
+            UserSecretKey::from_mnemonic(&zero).unwrap().get_public().to_string(),
+            "lorenz1j3kf7gla6th5adq8whwy27u7gtc9dgqp8ap5dw97hnuqdx7l4yuqrz4paz"
+        );
+        let bad_checksum = vec!["abandon"; MNEMONIC_WORDS].join(" ");
+        assert!(matches!(UserSecretKey::from_mnemonic(&bad_checksum), Err(Error::BadMnemonic(_))));
+        let short = words.split(' ').take(12).collect::<Vec<_>>().join(" ");
+        assert!(matches!(UserSecretKey::from_mnemonic(&short), Err(Error::BadMnemonic(_))));
+        assert!(matches!(UserSecretKey::from_mnemonic("not a mnemonic"), Err(Error::BadMnemonic(_))));
+    }
+}
@@ -0,0 +1,86 @@
+use crate::encryption::{self, Scheme};
+use crate::header::{self, Stanza};
+use crate::recipient::{Identity, Recipient, UnwrapContext, WrapContext};
+use crate::{Error, Secret};
+use ring::rand::{SecureRandom, SystemRandom};
+use scrypt::{scrypt, Params};
+
+pub const SALT_LEN: usize = 16;
+/// The default scrypt work factor (log2 of N), takes about a second and 256MiB of memory.
+pub const DEFAULT_WORK_FACTOR: u8 = 18;
+/// Higher work factors are rejected so a malicious file can't make us spend unbounded memory and time (1GiB at most).
+pub const MAX_WORK_FACTOR: u8 = 20;
+
+const PASSPHRASE_LABEL: &[u8] = b"lorenz-passphrase-recipient";
+
+/// A passphrase that can decrypt a file instead of a private key.
+/// Wrapping uses `work_factor`, unwrapping uses the one stored in the stanza but refuses any higher than `work_factor`,
+/// so a file can't make decrypting it as slow as it wants.
+pub(crate) struct Passphrase {
+    passphrase: Secret,
+    work_factor: u8,
+}
+
+impl Passphrase {
+    pub fn new(passphrase: Secret, work_factor: u8) -> Self {
+        Passphrase { passphrase, work_factor }
+    }
+
+    /// Returns `None` if the passphrase is wrong, but fails on a malformed stanza.
+    fn unwrap_stanza(&self, stanza: &Stanza, scheme: Scheme) -> Result<Option<Secret>, Error> {
+        if stanza.body.len() != 1 + SALT_LEN + scheme.get_encrypted_key_size() {
+            return Err(Error::BadHeader);
+        }
+        let (&work_factor, rest) = stanza.body.split_first().unwrap();
+        if work_factor > self.work_factor {
+            return Err(Error::BadWorkFactor(work_factor));
+        }
+        let (salt, enc_key) = rest.split_at(SALT_LEN);
+        let key = derive_key(self.passphrase.as_ref(), PASSPHRASE_LABEL, salt, work_factor)?;
+        Ok(encryption::decrypt_data(key.as_ref(), enc_key.to_vec(), scheme).ok().map(Secret::from_vec))
+    }
+}
+
+impl Recipient for Passphrase {
+    fn wrap_file_key(&self, file_key: &Secret, ctx: &WrapContext) -> Result<Stanza, Error> {
+        let salt = generate_salt()?;
+        let key = derive_key(self.passphrase.as_ref(), PASSPHRASE_LABEL, &salt, self.work_factor)?;
+        let enc_key = encryption::encrypt_data(key.as_ref(), file_key.clone().into_vec(), ctx.scheme())?;
+
+        let mut body = vec![self.work_factor];
+        body.extend_from_slice(&salt);
+        body.extend_from_slice(&enc_key);
+        Ok(Stanza::new(header::STANZA_PASSPHRASE, body))
+    }
+}
+
+impl Identity for Passphrase {
+    fn unwrap_file_key(&self, ctx: &UnwrapContext) -> Result<Option<Secret>, Error> {
+        // `Header::read` rejects files with more than one passphrase stanza.
+        match ctx.stanzas().iter().find(|s| s.kind == header::STANZA_PASSPHRASE) {
+            Some(stanza) => self.unwrap_stanza(stanza, ctx.scheme()),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Derives a 32 bytes key from a passphrase with scrypt.
+/// `label` separates keys derived for different purposes from the same passphrase and salt.
+pub fn derive_key(passphrase: &[u8], label: &[u8], salt: &[u8], work_factor: u8) -> Result<Secret, Error> {
+    if work_factor == 0 || work_factor > MAX_WORK_FACTOR {
+        return Err(Error::BadWorkFactor(work_factor));
+    }
+    let params = Params::new(work_factor, 8, 1, 32).map_err(|_| Error::BadWorkFactor(work_factor))?;
+    let mut labeled_salt = label.to_vec();
+    labeled_salt.extend_from_slice(salt);
+
+    let mut key = vec![0u8; 32];
+    scrypt(passphrase, &labeled_salt, &params, &mut key).map_err(|_| Error::BadKeyLength)?;
+    Ok(Secret::from_vec(key))
+}
+
+pub fn generate_salt() -> Result<[u8; SALT_LEN], Error> {
+    let mut salt = [0u8; SALT_LEN];
+    SystemRandom::new().fill(&mut salt)?;
+    Ok(salt)
+}
@@ -0,0 +1,122 @@
+//! The extension points for new kinds of keys: a [`Recipient`] wraps the file key into a stanza of the header,
+//! and an [`Identity`] finds its own stanzas and unwraps the file key from them.
+//! Every kind of key has its own stanza kind, stanzas of a kind a version doesn't know are skipped.
+
+use crate::encryption::{self, Scheme};
+use crate::header::{self, Header, Stanza, HINT_LEN};
+use crate::x25519::{EphemeralKey, UserPublicKey, UserSecretKey};
+use crate::{Error, Fingerprint, Secret};
+use ring::digest::SHA256;
+use ring::hkdf;
+use ring::hmac::SigningKey;
+use x25519_dalek::PublicKey;
+
+/// Something the file key can be wrapped to, like a `UserPublicKey`.
+pub trait Recipient {
+    /// Identifies the recipient, a recipient given more than once only gets a single stanza.
+    /// Recipients without a fingerprint (the default) are never deduplicated.
+    fn fingerprint(&self) -> Option<Fingerprint> {
+        None
+    }
+
+    /// Wraps `file_key` into a stanza only this recipient can unwrap.
+    fn wrap_file_key(&self, file_key: &Secret, ctx: &WrapContext) -> Result<Stanza, Error>;
+}
+
+impl<R: Recipient + ?Sized> Recipient for Box<R> {
+    fn fingerprint(&self) -> Option<Fingerprint> {
+        (**self).fingerprint()
+    }
+
+    fn wrap_file_key(&self, file_key: &Secret, ctx: &WrapContext) -> Result<Stanza, Error> {
+        (**self).wrap_file_key(file_key, ctx)
+    }
+}
+
+/// Something that can unwrap the file key from its own stanzas, like a `UserSecretKey`.
+pub trait Identity {
+    /// Returns `Ok(None)` when none of the stanzas is for this identity,
+    /// and an error when one of them is but can't be unwrapped.
+    fn unwrap_file_key(&self, ctx: &UnwrapContext) -> Result<Option<Secret>, Error>;
+}
+
+/// What a recipient gets to know about the file it wraps the file key for.
+pub struct WrapContext<'a> {
+    pub(crate) ephemeral: &'a EphemeralKey,
+    pub(crate) sender: Option<&'a UserSecretKey>,
+    pub(crate) scheme: Scheme,
+    pub(crate) hints: bool,
+}
+
+impl WrapContext<'_> {
+    pub fn scheme(&self) -> Scheme {
+        self.scheme
+    }
+
+    /// Whether stanzas start with a recipient hint, see `Encryptor::recipient_hints`.
+    pub fn hints(&self) -> bool {
+        self.hints
+    }
+}
+
+/// What an identity gets to know about the file it looks for its stanzas in.
+pub struct UnwrapContext<'a> {
+    pub(crate) header: &'a Header,
+    pub(crate) expected_sender: Option<&'a UserPublicKey>,
+}
+
+impl UnwrapContext<'_> {
+    pub fn scheme(&self) -> Scheme {
+        self.header.scheme
+    }
+
+    /// Whether stanzas start with a recipient hint, see `Encryptor::recipient_hints`.
+    pub fn hints(&self) -> bool {
+        self.header.flags & header::FLAG_RECIPIENT_HINTS != 0
+    }
+
+    pub fn stanzas(&self) -> &[Stanza] {
+        &self.header.stanzas
+    }
+
+    pub(crate) fn ephemeral(&self) -> &PublicKey {
+        &self.header.ephemeral
+    }
+
+    /// Unwraps the file key from the stanzas of `kind` with `unwrap`, which gets their bodies without the hint.
+    /// With hints only the stanzas starting with `hint` are tried, and failing to unwrap them means they're corrupted.
+    pub fn unwrap_stanzas<F>(&self, kind: u8, hint: &[u8; HINT_LEN], unwrap: F) -> Result<Option<Secret>, Error>
+    where
+        F: FnMut(&[u8]) -> Option<Secret>,
+    {
+        let stanzas = self.stanzas().iter().filter(|s| s.kind == kind);
+        if !self.hints() {
+            return Ok(stanzas.map(|s| &s.body[..]).find_map(unwrap));
+        }
+        let mut ours = stanzas.filter(|s| s.body.starts_with(hint)).map(|s| &s.body[HINT_LEN..]).peekable();
+        if ours.peek().is_none() {
+            return Ok(None);
+        }
+        ours.find_map(unwrap).map(Some).ok_or(Error::BadStanza)
+    }
+}
+
+/// The file key encrypted under `key`, or `None` if `encrypted_key` wasn't encrypted under it.
+pub(crate) fn decrypt_file_key(key: &Secret, encrypted_key: &[u8], scheme: Scheme) -> Option<Secret> {
+    encryption::decrypt_data(key.as_ref(), encrypted_key.to_vec(), scheme).ok().map(Secret::from_vec)
+}
+
+/// Wraps the file key with the shared secret of a recipient and the header's ephemeral key.
+pub(crate) fn wrap_with_shared(kind: u8, file_key: &Secret, shared: &Secret, hints: bool, scheme: Scheme) -> Result<Stanza, Error> {
+    let mut body = if hints { recipient_hint(shared).to_vec() } else { Vec::new() };
+    body.extend(encryption::encrypt_data(shared.as_ref(), file_key.clone().into_vec(), scheme)?);
+    Ok(Stanza::new(kind, body))
+}
+
+/// The hint derived from a secret shared with the recipient, a `Recipient` starts its stanza with it when `WrapContext::hints` is set,
+/// and its `Identity` looks for it with `UnwrapContext::unwrap_stanzas`.
+pub fn recipient_hint(shared: &Secret) -> [u8; HINT_LEN] {
+    let mut hint = [0u8; HINT_LEN];
+    hkdf::extract_and_expand(&SigningKey::new(&SHA256, &[]), shared.as_ref(), b"recipient-hint", &mut hint);
+    hint
+}
@@ -5,8 +5,7 @@ use std::boxed::Box;
 use std::pin::Pin;
 use zeroize::Zeroize;
 
-#[derive(Clone, Zeroize)]
-#[zeroize(drop)]
+#[derive(Clone)]
 pub struct Secret(Pin<Box<[u8]>>);
 
 impl Secret {
@@ -32,3 +31,22 @@ impl AsRef<[u8]> for Secret {
         &self.0
     }
 }
+
+impl AsMut<[u8]> for Secret {
+    #[inline]
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+impl Zeroize for Secret {
+    fn zeroize(&mut self) {
+        self.0.as_mut().get_mut().zeroize()
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.zeroize()
+    }
+}
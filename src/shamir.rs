@@ -0,0 +1,139 @@
+//! Shamir secret sharing over GF(256), so a file key can be split into shares of which any `threshold` recover it.
+
+use crate::{Error, Secret};
+use ring::rand::{SecureRandom, SystemRandom};
+use zeroize::Zeroizing;
+
+/// A single share of a secret, `index` is the point the polynomials were evaluated at and is never 0.
+#[derive(Clone)]
+pub struct Share {
+    pub index: u8,
+    pub threshold: u8,
+    pub value: Secret,
+}
+
+/// Multiplication in GF(256) with the AES polynomial, without secret dependent branches or lookups.
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut res = 0u8;
+    for _ in 0..8 {
+        res ^= a & 0u8.wrapping_sub(b & 1);
+        let carry = 0u8.wrapping_sub(a >> 7);
+        a = (a << 1) ^ (0x1b & carry);
+        b >>= 1;
+    }
+    res
+}
+
+/// `a^254`, which is the inverse of `a` for any non zero `a`.
+fn inv(a: u8) -> u8 {
+    let mut res = 1u8;
+    let mut square = a;
+    for bit in 0..8 {
+        if (254u8 >> bit) & 1 == 1 {
+            res = mul(res, square);
+        }
+        square = mul(square, square);
+    }
+    res
+}
+
+/// Splits `secret` into `shares` shares, any `threshold` of them recover it and fewer reveal nothing about it.
+pub fn split(secret: &[u8], threshold: u8, shares: u8) -> Result<Vec<Share>, Error> {
+    if threshold < 2 || threshold > shares {
+        return Err(Error::BadThreshold(threshold, shares.into()));
+    }
+    // The constant term of every byte's polynomial is the secret byte, the other coefficients are random.
+    let mut coefficients = Zeroizing::new(vec![0u8; secret.len() * usize::from(threshold - 1)]);
+    SystemRandom::new().fill(&mut coefficients)?;
+    Ok((1..=shares)
+        .map(|index| {
+            let value = secret
+                .iter()
+                .zip(coefficients.chunks(usize::from(threshold - 1)))
+                .map(|(&byte, row)| {
+                    // Horner's method, from the highest coefficient down to the secret byte.
+                    let higher = row.iter().rev().fold(0u8, |acc, &c| mul(acc, index) ^ c);
+                    mul(higher, index) ^ byte
+                })
+                .collect();
+            Share { index, threshold, value: Secret::from_vec(value) }
+        })
+        .collect())
+}
+
+/// Recovers the secret from at least `threshold` shares with distinct indexes.
+pub fn combine(shares: &[Share]) -> Result<Secret, Error> {
+    let first = shares.first().ok_or(Error::NotEnoughShares(0, 0))?;
+    let threshold = usize::from(first.threshold);
+    let len = first.value.as_ref().len();
+    // A share that claims a threshold of one would be the secret itself, `split` never makes those.
+    if first.threshold < 2 {
+        return Err(Error::BadThreshold(first.threshold, shares.len()));
+    }
+    if shares.len() < threshold {
+        return Err(Error::NotEnoughShares(shares.len(), first.threshold));
+    }
+    let shares = &shares[..threshold];
+    for (i, share) in shares.iter().enumerate() {
+        let duplicate = shares[..i].iter().any(|other| other.index == share.index);
+        if share.index == 0 || duplicate || share.threshold != first.threshold || share.value.as_ref().len() != len {
+            return Err(Error::BadStanza);
+        }
+    }
+    let mut secret = Secret::from_vec(vec![0u8; len]);
+    for share in shares {
+        // The Lagrange basis polynomial of this share evaluated at 0, subtraction is xor in GF(256).
+        let basis = shares
+            .iter()
+            .filter(|other| other.index != share.index)
+            .fold(1u8, |acc, other| mul(acc, mul(other.index, inv(other.index ^ share.index))));
+        for (byte, &y) in secret.as_mut().iter_mut().zip(share.value.as_ref()) {
+            *byte ^= mul(y, basis);
+        }
+    }
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gf256() {
+        assert_eq!(mul(0x53, 0xca), 0x01);
+        assert_eq!(mul(0x57, 0x83), 0xc1);
+        for a in 1..=255u8 {
+            assert_eq!(mul(a, inv(a)), 1);
+        }
+    }
+
+    #[test]
+    fn split_and_combine() {
+        let secret = Secret::generate32().unwrap();
+        let shares = split(secret.as_ref(), 3, 5).unwrap();
+        assert_eq!(shares.len(), 5);
+        let pick = |indexes: &[usize]| -> Vec<Share> {
+            indexes.iter().map(|&i| Share { index: shares[i].index, threshold: 3, value: shares[i].value.clone() }).collect()
+        };
+        for indexes in &[[0, 1, 2], [4, 2, 0], [1, 3, 4]] {
+            assert_eq!(combine(&pick(indexes)).unwrap().as_ref(), secret.as_ref());
+        }
+        assert_eq!(combine(&pick(&[3, 1, 0, 4])).unwrap().as_ref(), secret.as_ref());
+        assert!(matches!(combine(&pick(&[0, 1])), Err(Error::NotEnoughShares(2, 3))));
+        assert!(matches!(combine(&pick(&[0, 1, 1])), Err(Error::BadStanza)));
+        let mut tampered = pick(&[0, 1, 2]);
+        tampered[2].value.as_mut()[0] ^= 1;
+        assert_ne!(combine(&tampered).unwrap().as_ref(), secret.as_ref());
+
+        assert!(matches!(split(secret.as_ref(), 1, 5), Err(Error::BadThreshold(1, 5))));
+        assert!(matches!(split(secret.as_ref(), 6, 5), Err(Error::BadThreshold(6, 5))));
+    }
+
+    #[test]
+    fn combine_refuses_low_thresholds() {
+        let secret = Secret::generate32().unwrap();
+        let forged = |threshold| vec![Share { index: 1, threshold, value: secret.clone() }];
+        assert!(matches!(combine(&forged(1)), Err(Error::BadThreshold(1, 1))));
+        assert!(matches!(combine(&forged(0)), Err(Error::BadThreshold(0, 1))));
+    }
+}
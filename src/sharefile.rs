@@ -0,0 +1,71 @@
+//! Share files carry the shares of a threshold file's key from their holders to whoever combines them,
+//! so the holders never have to bring their private keys to the same machine.
+//! The content below is itself encrypted to the combiner as a regular lorenz file.
+//!
+//! Layout: `SHARE_MAGIC | file id | shares`, each share being `threshold | index | share of the file key`.
+
+use crate::header::Header;
+use crate::shamir::Share;
+use crate::{Error, Secret};
+use ring::digest::{self, SHA256};
+use zeroize::Zeroizing;
+
+const SHARE_MAGIC: &[u8] = b"LORENZ-SHARES";
+pub const FILE_ID_LEN: usize = 32;
+const KEY_LEN: usize = 32;
+const SHARE_LEN: usize = 2 + KEY_LEN;
+
+/// Identifies the file the shares belong to, so shares of different files aren't mixed up.
+/// It stays the same when recipients are added or removed.
+pub fn file_id(header: &Header) -> [u8; FILE_ID_LEN] {
+    let mut id = [0u8; FILE_ID_LEN];
+    id.copy_from_slice(digest::digest(&SHA256, &header.fixed_bytes()).as_ref());
+    id
+}
+
+pub fn encode(file_id: &[u8; FILE_ID_LEN], shares: &[Share]) -> Zeroizing<Vec<u8>> {
+    let mut content = Zeroizing::new(Vec::with_capacity(SHARE_MAGIC.len() + FILE_ID_LEN + shares.len() * SHARE_LEN));
+    content.extend_from_slice(SHARE_MAGIC);
+    content.extend_from_slice(file_id);
+    for share in shares {
+        content.extend_from_slice(&[share.threshold, share.index]);
+        content.extend_from_slice(share.value.as_ref());
+    }
+    content
+}
+
+pub fn decode(content: &[u8]) -> Result<([u8; FILE_ID_LEN], Vec<Share>), Error> {
+    let content = content.strip_prefix(SHARE_MAGIC).ok_or(Error::BadShareFile)?;
+    if content.len() < FILE_ID_LEN || !(content.len() - FILE_ID_LEN).is_multiple_of(SHARE_LEN) {
+        return Err(Error::BadShareFile);
+    }
+    let (id, shares) = content.split_at(FILE_ID_LEN);
+    let mut file_id = [0u8; FILE_ID_LEN];
+    file_id.copy_from_slice(id);
+    let shares = shares
+        .chunks(SHARE_LEN)
+        .map(|share| Share { threshold: share[0], index: share[1], value: Secret::from_vec(share[2..].to_vec()) })
+        .collect();
+    Ok((file_id, shares))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn share_file_content() {
+        let shares = vec![
+            Share { threshold: 2, index: 1, value: Secret::from_vec(vec![1u8; KEY_LEN]) },
+            Share { threshold: 2, index: 3, value: Secret::from_vec(vec![3u8; KEY_LEN]) },
+        ];
+        let content = encode(&[9u8; FILE_ID_LEN], &shares);
+        let (file_id, decoded) = decode(&content).unwrap();
+        assert_eq!(file_id, [9u8; FILE_ID_LEN]);
+        assert_eq!(decoded.len(), 2);
+        assert_eq!((decoded[1].threshold, decoded[1].index, decoded[1].value.as_ref()), (2, 3, &[3u8; KEY_LEN][..]));
+
+        assert!(matches!(decode(&content[..content.len() - 1]), Err(Error::BadShareFile)));
+        assert!(matches!(decode(&content[1..]), Err(Error::BadShareFile)));
+    }
+}
@@ -0,0 +1,226 @@
+use crate::bech32::{self, SIGNING_KEY_HRP, VERIFYING_KEY_HRP};
+use crate::x25519::{parse_key32, secret_parse_hex32};
+use crate::{Error, Secret};
+use ring::digest::{self, Context, Digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{self, Ed25519KeyPair, KeyPair, ED25519};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use untrusted::Input;
+
+pub const SIGNATURE_LEN: usize = 64;
+
+/// Signed files end their plaintext with `verifying key | signature`, inside the encrypted stream,
+/// so only the recipients can tell who signed the file.
+pub const TRAILER_LEN: usize = 32 + SIGNATURE_LEN;
+
+const SIGNATURE_LABEL: &[u8] = b"lorenz-signature";
+
+/// An Ed25519 key that signs the files it encrypts, so recipients can tell who sent them.
+#[derive(Clone)]
+pub struct UserSigningKey(Secret);
+
+/// The public half of a `UserSigningKey`, recipients check the signature of a file against it.
+#[derive(Clone, Copy)]
+pub struct UserVerifyingKey([u8; 32]);
+
+impl UserSigningKey {
+    pub fn new() -> Result<Self, Error> {
+        let mut seed = vec![0u8; 32];
+        SystemRandom::new().fill(&mut seed)?;
+        Ok(UserSigningKey(Secret::from_vec(seed)))
+    }
+
+    /// Parses a signing key in the older `0x` hex form, the caller has to know it's a signing key.
+    pub fn from_hex(hex: &str) -> Result<Self, Error> {
+        Ok(UserSigningKey(Secret::from_vec(secret_parse_hex32(hex)?.to_vec())))
+    }
+
+    fn key_pair(&self) -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(Input::from(self.0.as_ref())).expect("the seed is always 32 bytes")
+    }
+
+    pub fn get_verifying(&self) -> UserVerifyingKey {
+        let mut key = [0u8; 32];
+        key.copy_from_slice(self.key_pair().public_key().as_ref());
+        UserVerifyingKey(key)
+    }
+
+    fn sign(&self, msg: &[u8]) -> [u8; SIGNATURE_LEN] {
+        let mut signature = [0u8; SIGNATURE_LEN];
+        signature.copy_from_slice(self.key_pair().sign(msg).as_ref());
+        signature
+    }
+}
+
+impl UserVerifyingKey {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Parses a verifying key in the older `0x` hex form, the caller has to know it's a verifying key.
+    pub fn from_hex(hex: &str) -> Result<Self, Error> {
+        secret_parse_hex32(hex).map(|key| UserVerifyingKey(*key))
+    }
+
+    fn verify(&self, msg: &[u8], signature: &[u8]) -> Result<(), Error> {
+        signature::verify(&ED25519, Input::from(&self.0), Input::from(msg), Input::from(signature)).map_err(|_| Error::BadSignature)
+    }
+}
+
+/// The message that is signed: the fixed part of the header and the hash of the plaintext.
+/// The stanzas aren't signed so recipients can still be added or removed, they're covered by the header MAC.
+fn signed_message(header_hash: &Digest, payload_hash: &Digest) -> Vec<u8> {
+    let mut msg = SIGNATURE_LABEL.to_vec();
+    msg.extend_from_slice(header_hash.as_ref());
+    msg.extend_from_slice(payload_hash.as_ref());
+    msg
+}
+
+/// Hashes the plaintext while it's encrypted, and produces the trailer at the end.
+pub(crate) struct Signer {
+    key: UserSigningKey,
+    header_hash: Digest,
+    payload: Context,
+}
+
+impl Signer {
+    pub fn new(key: UserSigningKey, header: &[u8]) -> Self {
+        Signer { key, header_hash: digest::digest(&SHA256, header), payload: Context::new(&SHA256) }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.payload.update(data);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        let msg = signed_message(&self.header_hash, &self.payload.finish());
+        let mut trailer = self.key.get_verifying().as_bytes().to_vec();
+        trailer.extend_from_slice(&self.key.sign(&msg));
+        trailer
+    }
+}
+
+/// Hashes the plaintext while it's decrypted, and checks the trailer at the end.
+pub(crate) struct Verifier {
+    expected: Option<UserVerifyingKey>,
+    header_hash: Digest,
+    payload: Context,
+}
+
+impl Verifier {
+    pub fn new(expected: Option<UserVerifyingKey>, header: &[u8]) -> Self {
+        Verifier { expected, header_hash: digest::digest(&SHA256, header), payload: Context::new(&SHA256) }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.payload.update(data);
+    }
+
+    /// Returns the key that signed the file.
+    pub fn finish(self, trailer: &[u8]) -> Result<UserVerifyingKey, Error> {
+        if trailer.len() != TRAILER_LEN {
+            return Err(Error::BadSignature);
+        }
+        let (key, signature) = trailer.split_at(32);
+        let mut signer = [0u8; 32];
+        signer.copy_from_slice(key);
+        let signer = UserVerifyingKey(signer);
+        if self.expected.is_some_and(|expected| expected != signer) {
+            return Err(Error::BadSignature);
+        }
+        signer.verify(&signed_message(&self.header_hash, &self.payload.finish()), signature)?;
+        Ok(signer)
+    }
+}
+
+impl PartialEq for UserVerifyingKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for UserVerifyingKey {}
+
+impl Hash for UserVerifyingKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+impl fmt::Display for UserVerifyingKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&bech32::encode(VERIFYING_KEY_HRP, &self.0))
+    }
+}
+
+impl fmt::Debug for UserVerifyingKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "UserVerifyingKey({})", self)
+    }
+}
+
+impl FromStr for UserVerifyingKey {
+    type Err = Error;
+
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        parse_key32(key, VERIFYING_KEY_HRP).map(|key| UserVerifyingKey(*key))
+    }
+}
+
+impl fmt::Display for UserSigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "signingKey: {}", *bech32::encode(SIGNING_KEY_HRP, self.0.as_ref()))?;
+        writeln!(f, "verifyingKey: {}", self.get_verifying())
+    }
+}
+
+impl fmt::Debug for UserSigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl FromStr for UserSigningKey {
+    type Err = Error;
+
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        let seed = parse_key32(key, SIGNING_KEY_HRP)?;
+        Ok(UserSigningKey(Secret::from_vec(seed.to_vec())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signer_and_verifier() {
+        let key = UserSigningKey::new().unwrap();
+        let sign = |header: &[u8], payload: &[u8]| {
+            let mut signer = Signer::new(key.clone(), header);
+            signer.update(payload);
+            signer.finish()
+        };
+        let verify = |expected: Option<UserVerifyingKey>, header: &[u8], payload: &[u8], trailer: &[u8]| {
+            let mut verifier = Verifier::new(expected, header);
+            verifier.update(payload);
+            verifier.finish(trailer)
+        };
+
+        let trailer = sign(b"header", b"payload");
+        assert_eq!(trailer.len(), TRAILER_LEN);
+        assert_eq!(verify(None, b"header", b"payload", &trailer).unwrap(), key.get_verifying());
+        assert!(verify(Some(key.get_verifying()), b"header", b"payload", &trailer).is_ok());
+
+        let other = UserSigningKey::new().unwrap().get_verifying();
+        assert!(matches!(verify(Some(other), b"header", b"payload", &trailer), Err(Error::BadSignature)));
+        assert!(matches!(verify(None, b"other header", b"payload", &trailer), Err(Error::BadSignature)));
+        assert!(matches!(verify(None, b"header", b"other payload", &trailer), Err(Error::BadSignature)));
+        assert!(matches!(verify(None, b"header", b"payload", &trailer[1..]), Err(Error::BadSignature)));
+
+        let parsed: UserSigningKey = key.to_string().lines().next().unwrap()["signingKey: ".len()..].parse().unwrap();
+        assert_eq!(parsed.get_verifying(), key.get_verifying());
+    }
+}
@@ -0,0 +1,328 @@
+use crate::encryption::Scheme;
+use crate::signature::{Signer, Verifier, TRAILER_LEN};
+use crate::{Error, Secret, UserVerifyingKey};
+use ring::aead::{open_in_place, seal_in_place, Aad, Nonce, OpeningKey, SealingKey, NONCE_LEN};
+use ring::digest::SHA256;
+use ring::hkdf;
+use ring::hmac::SigningKey;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::cmp;
+use std::io::{self, Read, Write};
+use zeroize::Zeroizing;
+
+/// The size of a plaintext chunk, every chunk except the last one is exactly this size.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+pub const PAYLOAD_NONCE_LEN: usize = 16;
+const TAG_LEN: usize = 16;
+const ENCRYPTED_CHUNK_SIZE: usize = CHUNK_SIZE + TAG_LEN;
+
+/// Derives the payload key from the file key and the random payload nonce,
+/// so the file key itself is never used directly on the payload.
+fn payload_key(file_key: &Secret, nonce: &[u8], scheme: Scheme) -> Secret {
+    let mut key = vec![0u8; scheme.get_algorithm().key_len()];
+    hkdf::extract_and_expand(&SigningKey::new(&SHA256, nonce), file_key.as_ref(), b"payload", &mut key);
+    Secret::from_vec(key)
+}
+
+/// The nonce of a chunk is an 11 bytes big endian counter followed by a byte marking the last chunk.
+fn chunk_nonce(counter: u64, last: bool) -> Nonce {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[3..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last as u8;
+    Nonce::assume_unique_for_key(nonce)
+}
+
+/// The length of the plaintext in a stream of `len` encrypted bytes, `None` if no stream can have that length.
+pub fn plaintext_len(len: u64) -> Option<u64> {
+    let len = len.checked_sub(PAYLOAD_NONCE_LEN as u64)?;
+    let chunks = cmp::max(1, len.div_ceil(ENCRYPTED_CHUNK_SIZE as u64));
+    let last = len - (chunks - 1) * ENCRYPTED_CHUNK_SIZE as u64;
+    // Same as in `StreamReader::open_chunk`, only an empty stream may have an empty last chunk.
+    if last < TAG_LEN as u64 || (chunks > 1 && last == TAG_LEN as u64) {
+        return None;
+    }
+    Some(len - chunks * TAG_LEN as u64)
+}
+
+/// Encrypts everything written to it in `CHUNK_SIZE` chunks.
+/// `finish` must be called to seal the last chunk, otherwise the output will be truncated.
+pub struct StreamWriter<W: Write> {
+    key: SealingKey,
+    output: W,
+    buffer: Zeroizing<Vec<u8>>,
+    counter: u64,
+    signer: Option<Signer>,
+}
+
+impl<W: Write> StreamWriter<W> {
+    pub fn new(file_key: &Secret, scheme: Scheme, mut output: W) -> Result<Self, Error> {
+        let mut nonce = [0u8; PAYLOAD_NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce)?;
+        output.write_all(&nonce)?;
+
+        let key = payload_key(file_key, &nonce, scheme);
+        let key = SealingKey::new(scheme.get_algorithm(), key.as_ref())?;
+        Ok(StreamWriter { key, output, buffer: Zeroizing::new(Vec::with_capacity(ENCRYPTED_CHUNK_SIZE)), counter: 0, signer: None })
+    }
+
+    /// Signs everything written, the signature is appended to the plaintext by `finish`.
+    pub(crate) fn with_signer(mut self, signer: Signer) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    fn seal_chunk(&mut self, last: bool) -> Result<(), Error> {
+        let nonce = chunk_nonce(self.counter, last);
+        self.counter = self.counter.checked_add(1).ok_or(Error::RingError)?;
+
+        self.buffer.extend_from_slice(&[0u8; TAG_LEN]);
+        let len = seal_in_place(&self.key, nonce, Aad::empty(), &mut self.buffer, TAG_LEN)?;
+        debug_assert_eq!(len, self.buffer.len());
+        self.output.write_all(&self.buffer)?;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Seals the last chunk and returns the underlying writer.
+    pub fn finish(mut self) -> Result<W, Error> {
+        if let Some(signer) = self.signer.take() {
+            self.write_all(&signer.finish())?;
+        }
+        self.seal_chunk(true)?;
+        self.output.flush()?;
+        Ok(self.output)
+    }
+}
+
+impl<W: Write> Write for StreamWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if let Some(signer) = &mut self.signer {
+            signer.update(data);
+        }
+        let mut written = 0;
+        while written < data.len() {
+            // A full chunk is only sealed once more data arrives, so we know it isn't the last one.
+            if self.buffer.len() == CHUNK_SIZE {
+                self.seal_chunk(false)?;
+            }
+            let amount = cmp::min(CHUNK_SIZE - self.buffer.len(), data.len() - written);
+            self.buffer.extend_from_slice(&data[written..written + amount]);
+            written += amount;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// Decrypts a stream written by `StreamWriter`, chunk by chunk.
+/// Fails if chunks were truncated, reordered, modified, or if data was appended after the last chunk.
+///
+/// Every chunk is authenticated before its bytes are returned, but a truncated stream is only noticed at its end,
+/// and so is a bad signature. The bytes read so far are unverified until `read` returned EOF (`Ok(0)`):
+/// a caller that gets an error instead must discard everything it read, or write it somewhere it can delete it from.
+pub struct StreamReader<R: Read> {
+    key: OpeningKey,
+    input: R,
+    buffer: Zeroizing<Vec<u8>>,
+    plaintext: (usize, usize),
+    counter: u64,
+    finished: bool,
+    verifier: Option<Verifier>,
+    held_back: Zeroizing<Vec<u8>>,
+    signer: Option<UserVerifyingKey>,
+}
+
+impl<R: Read> StreamReader<R> {
+    pub fn new(file_key: &Secret, scheme: Scheme, mut input: R) -> Result<Self, Error> {
+        let mut nonce = [0u8; PAYLOAD_NONCE_LEN];
+        input.read_exact(&mut nonce).map_err(Error::truncated)?;
+
+        let key = payload_key(file_key, &nonce, scheme);
+        let key = OpeningKey::new(scheme.get_algorithm(), key.as_ref())?;
+        let buffer = Zeroizing::new(Vec::with_capacity(ENCRYPTED_CHUNK_SIZE + 1));
+        Ok(StreamReader {
+            key,
+            input,
+            buffer,
+            plaintext: (0, 0),
+            counter: 0,
+            finished: false,
+            verifier: None,
+            held_back: Zeroizing::new(Vec::new()),
+            signer: None,
+        })
+    }
+
+    /// Strips the signature from the end of the plaintext and checks it once the stream ends.
+    pub(crate) fn with_verifier(mut self, verifier: Verifier) -> Self {
+        self.verifier = Some(verifier);
+        self
+    }
+
+    /// The key that signed the file, known only after the whole stream was read.
+    pub fn signer(&self) -> Option<UserVerifyingKey> {
+        self.signer
+    }
+
+    /// Reads the next chunk and decrypts it into the buffer.
+    /// One byte past the chunk is read ahead to find out if this is the last chunk.
+    fn open_chunk(&mut self) -> Result<(), Error> {
+        // Drop the previous chunk but keep the byte we read ahead.
+        let (_, end) = self.plaintext;
+        let ahead = self.buffer.len().saturating_sub(end + TAG_LEN);
+        let len = self.buffer.len();
+        self.buffer.copy_within(len - ahead.., 0);
+        self.buffer.truncate(ahead);
+
+        while self.buffer.len() <= ENCRYPTED_CHUNK_SIZE {
+            let mut buf = [0u8; 8 * 1024];
+            let wanted = cmp::min(buf.len(), ENCRYPTED_CHUNK_SIZE + 1 - self.buffer.len());
+            match self.input.read(&mut buf[..wanted]) {
+                Ok(0) => break,
+                Ok(n) => self.buffer.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let last = self.buffer.len() <= ENCRYPTED_CHUNK_SIZE;
+        let chunk_len = cmp::min(self.buffer.len(), ENCRYPTED_CHUNK_SIZE);
+        // Only an empty stream may have an empty last chunk.
+        if chunk_len < TAG_LEN || (last && self.counter != 0 && chunk_len == TAG_LEN) {
+            return Err(Error::Truncated);
+        }
+
+        let (nonce, counter) = (chunk_nonce(self.counter, last), self.counter);
+        self.counter = self.counter.checked_add(1).ok_or(Error::RingError)?;
+        // A failed open wipes the chunk, so keep a copy of the last one to tell a truncated stream from a corrupted one.
+        let copy = if last { Some(Zeroizing::new(self.buffer[..chunk_len].to_vec())) } else { None };
+        let opened = open_in_place(&self.key, nonce, Aad::empty(), 0, &mut self.buffer[..chunk_len]).map(|plaintext| plaintext.len());
+        let len = match (opened, copy) {
+            (Ok(len), _) => len,
+            (Err(_), Some(mut copy)) => {
+                // A chunk that opens as a non-final one means the stream was cut right after it.
+                let non_final = open_in_place(&self.key, chunk_nonce(counter, false), Aad::empty(), 0, &mut copy).is_ok();
+                return Err(if non_final { Error::Truncated } else { Error::CorruptedChunk(counter) });
+            }
+            (Err(_), None) => return Err(Error::CorruptedChunk(counter)),
+        };
+        self.plaintext = (0, len);
+        self.finished = last;
+        Ok(())
+    }
+
+    fn read_plaintext(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.plaintext.0 == self.plaintext.1 {
+            if self.finished {
+                return Ok(0);
+            }
+            self.open_chunk()?;
+        }
+        let (start, end) = self.plaintext;
+        let amount = cmp::min(buf.len(), end - start);
+        buf[..amount].copy_from_slice(&self.buffer[start..start + amount]);
+        self.plaintext.0 += amount;
+        Ok(amount)
+    }
+
+    /// Keeps the last `TRAILER_LEN` bytes of the plaintext back, they're the signature and not part of the data.
+    fn read_signed(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut chunk = Zeroizing::new(vec![0u8; 8 * 1024]);
+        while self.held_back.len() <= TRAILER_LEN {
+            let n = self.read_plaintext(&mut chunk)?;
+            if n == 0 {
+                let verifier = self.verifier.take().expect("only called with a verifier");
+                self.signer = Some(verifier.finish(&self.held_back)?);
+                return Ok(0);
+            }
+            self.held_back.extend_from_slice(&chunk[..n]);
+        }
+        let amount = cmp::min(buf.len(), self.held_back.len() - TRAILER_LEN);
+        buf[..amount].copy_from_slice(&self.held_back[..amount]);
+        self.verifier.as_mut().expect("only called with a verifier").update(&buf[..amount]);
+        self.held_back.drain(..amount);
+        Ok(amount)
+    }
+}
+
+impl<R: Read> Read for StreamReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match (&self.verifier, self.signer) {
+            (Some(_), _) => self.read_signed(buf),
+            // The signature was already checked.
+            (None, Some(_)) => Ok(0),
+            (None, None) => self.read_plaintext(buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{thread_rng, RngCore};
+
+    fn encrypt(key: &Secret, data: &[u8]) -> Vec<u8> {
+        let mut writer = StreamWriter::new(key, Scheme::Chacha20Poly1305, Vec::new()).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decrypt(key: &Secret, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut reader = StreamReader::new(key, Scheme::Chacha20Poly1305, data)?;
+        let mut result = Vec::new();
+        reader.read_to_end(&mut result)?;
+        Ok(result)
+    }
+
+    #[test]
+    fn stream_roundtrip() {
+        let key = Secret::generate32().unwrap();
+        let mut rng = thread_rng();
+        for &len in &[0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE, 3 * CHUNK_SIZE + 17] {
+            let mut data = vec![0u8; len];
+            rng.fill_bytes(&mut data);
+            let encrypted = encrypt(&key, &data);
+            let chunks = cmp::max(1, len.div_ceil(CHUNK_SIZE));
+            assert_eq!(encrypted.len(), PAYLOAD_NONCE_LEN + len + chunks * TAG_LEN);
+            assert_eq!(decrypt(&key, &encrypted).unwrap(), data);
+            assert_eq!(plaintext_len(encrypted.len() as u64), Some(len as u64));
+        }
+    }
+
+    #[test]
+    fn stream_detects_truncation_and_reordering() {
+        let key = Secret::generate32().unwrap();
+        let data = vec![42u8; 3 * CHUNK_SIZE + 5];
+        let encrypted = encrypt(&key, &data);
+        let body = &encrypted[PAYLOAD_NONCE_LEN..];
+        let chunk = |i: usize| &body[i * ENCRYPTED_CHUNK_SIZE..cmp::min(body.len(), (i + 1) * ENCRYPTED_CHUNK_SIZE)];
+
+        // Cut at a chunk boundary.
+        let truncated = &encrypted[..PAYLOAD_NONCE_LEN + 2 * ENCRYPTED_CHUNK_SIZE];
+        assert!(matches!(decrypt(&key, truncated), Err(Error::Truncated)));
+
+        // Cut in the middle of the last chunk.
+        assert!(decrypt(&key, &encrypted[..encrypted.len() - 3]).is_err());
+
+        // Swap two chunks.
+        let mut reordered = encrypted[..PAYLOAD_NONCE_LEN].to_vec();
+        for &i in &[1, 0, 2, 3] {
+            reordered.extend_from_slice(chunk(i));
+        }
+        assert!(matches!(decrypt(&key, &reordered), Err(Error::CorruptedChunk(0))));
+
+        // Append data after the last chunk.
+        let mut extended = encrypted.clone();
+        extended.push(0);
+        assert!(decrypt(&key, &extended).is_err());
+
+        assert_eq!(plaintext_len(PAYLOAD_NONCE_LEN as u64 + 3), None);
+        assert_eq!(plaintext_len((PAYLOAD_NONCE_LEN + ENCRYPTED_CHUNK_SIZE + TAG_LEN) as u64), None);
+
+        // Wrong key.
+        assert!(decrypt(&Secret::generate32().unwrap(), &encrypted).is_err());
+    }
+}
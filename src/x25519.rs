@@ -1,3 +1,7 @@
+use crate::bech32::{self, PUBLIC_KEY_HRP, SECRET_KEY_HRP};
+use crate::fingerprint::Fingerprint;
+use crate::header::{self, Stanza};
+use crate::recipient::{decrypt_file_key, recipient_hint, wrap_with_shared, Identity, Recipient, UnwrapContext, WrapContext};
 use crate::secret::Secret;
 use crate::Error;
 use lazy_static::lazy_static;
@@ -5,14 +9,29 @@ use rand_os::OsRng;
 use ring::digest::SHA256;
 use ring::hkdf;
 use ring::hmac::SigningKey;
-use rustc_hex::{FromHex, ToHex};
+use rustc_hex::FromHex;
 use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::str::FromStr;
 use x25519_dalek::{PublicKey, StaticSecret};
 use zeroize::{Zeroize, Zeroizing};
 
 lazy_static! {
-    static ref SALT: SigningKey = SigningKey::new(&SHA256, b"Lorenz");
+    pub(crate) static ref SALT: SigningKey = SigningKey::new(&SHA256, b"Lorenz");
+}
+
+const AUTHENTICATED_INFO: &[u8] = b"authenticated-sender";
+const DERIVE_INFO: &[u8] = b"lorenz-derive/";
+
+/// Derives the wrapping key from both the ephemeral-static and the static-static shared secrets,
+/// so only the holder of the sender's private key could have derived it too.
+fn derive_authenticated(ephemeral_shared: &[u8; 32], static_shared: &[u8; 32], len: usize) -> Secret {
+    let mut ikm = Zeroizing::new([0u8; 64]);
+    ikm[..32].copy_from_slice(ephemeral_shared);
+    ikm[32..].copy_from_slice(static_shared);
+    let mut res = vec![0u8; len];
+    hkdf::extract_and_expand(&SALT, &ikm[..], AUTHENTICATED_INFO, &mut res);
+    Secret::from_vec(res)
 }
 
 pub struct EphemeralKey(StaticSecret);
@@ -25,12 +44,24 @@ impl EphemeralKey {
 
     pub fn derive_secret(&self, other: &PublicKey, len: usize) -> Secret {
         let mut res = vec![0u8; len];
-        let shared = self.0.diffie_hellman(&other);
+        let shared = self.0.diffie_hellman(other);
         hkdf::extract_and_expand(&SALT, shared.as_bytes(), &[], &mut res);
 
         Secret::from_vec(res)
     }
 
+    /// The raw shared secret, for key agreements that combine it with other secrets.
+    pub(crate) fn diffie_hellman(&self, other: &PublicKey) -> Zeroizing<[u8; 32]> {
+        Zeroizing::new(*self.0.diffie_hellman(other).as_bytes())
+    }
+
+    /// The sender's side of `UserSecretKey::derive_authenticated_secret`.
+    pub fn derive_authenticated_secret(&self, sender: &UserSecretKey, recipient: &PublicKey, len: usize) -> Secret {
+        let ephemeral_shared = self.0.diffie_hellman(recipient);
+        let static_shared = sender.0.diffie_hellman(recipient);
+        derive_authenticated(ephemeral_shared.as_bytes(), static_shared.as_bytes(), len)
+    }
+
     pub fn get_public(&self) -> PublicKey {
         PublicKey::from(&self.0)
     }
@@ -54,25 +85,166 @@ impl UserSecretKey {
 
     pub fn derive_secret(&self, other: &PublicKey, len: usize) -> Secret {
         let mut res = vec![0u8; len];
-        let shared = self.0.diffie_hellman(&other);
+        let shared = self.0.diffie_hellman(other);
         hkdf::extract_and_expand(&SALT, shared.as_bytes(), &[], &mut res);
 
         Secret::from_vec(res)
     }
 
-    pub fn get_public(&self) -> PublicKey {
-        PublicKey::from(&self.0)
+    /// The raw shared secret, for key agreements that combine it with other secrets.
+    pub(crate) fn diffie_hellman(&self, other: &PublicKey) -> Zeroizing<[u8; 32]> {
+        Zeroizing::new(*self.0.diffie_hellman(other).as_bytes())
+    }
+
+    /// The shared secret of a file from an authenticated `sender`, it can't be derived without the sender's private key.
+    pub fn derive_authenticated_secret(&self, ephemeral: &PublicKey, sender: &PublicKey, len: usize) -> Secret {
+        let ephemeral_shared = self.0.diffie_hellman(ephemeral);
+        let static_shared = self.0.diffie_hellman(sender);
+        derive_authenticated(ephemeral_shared.as_bytes(), static_shared.as_bytes(), len)
+    }
+
+    /// Derives an independent subkey for a `/` separated label like `project/payments/2026`.
+    /// Each component is a derivation step, so `derive("a/b")` is `derive("a")` followed by `derive("b")`,
+    /// and anyone holding a subkey can derive the keys below it but not the ones above.
+    pub fn derive(&self, label: &str) -> Result<UserSecretKey, Error> {
+        if label.split('/').any(str::is_empty) {
+            return Err(Error::BadDerivationLabel(label.to_owned()));
+        }
+        let mut key = UserSecretKey::from_bytes(&self.to_bytes());
+        for component in label.split('/') {
+            let mut child = Zeroizing::new([0u8; 32]);
+            hkdf::extract_and_expand(&SALT, &key.to_bytes()[..], &[DERIVE_INFO, component.as_bytes()].concat(), &mut child[..]);
+            key = UserSecretKey::from_bytes(&child);
+        }
+        Ok(key)
+    }
+
+    pub fn get_public(&self) -> UserPublicKey {
+        UserPublicKey(PublicKey::from(&self.0))
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8; 32]) -> Self {
+        UserSecretKey(StaticSecret::from(*bytes))
+    }
+
+    pub(crate) fn to_bytes(&self) -> Zeroizing<[u8; 32]> {
+        Zeroizing::new(self.0.to_bytes())
+    }
+
+    /// Parses a private key in the older `0x` hex form, the caller has to know it's a private key.
+    pub fn from_hex(hex: &str) -> Result<Self, Error> {
+        secret_parse_hex32(hex).map(|key| UserSecretKey::from_bytes(&key))
+    }
+}
+
+/// The public key of a recipient, files encrypted to it can be decrypted with the matching `UserSecretKey`.
+#[derive(Clone, Copy)]
+pub struct UserPublicKey(pub(crate) PublicKey);
+
+impl UserPublicKey {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        self.0.as_bytes()
+    }
+
+    pub fn fingerprint(&self) -> Fingerprint {
+        Fingerprint::of(self.as_bytes())
+    }
+
+    /// Parses a public key in the older `0x` hex form, the caller has to know it's a public key.
+    pub fn from_hex(hex: &str) -> Result<Self, Error> {
+        secret_parse_hex32(hex).map(|key| UserPublicKey::from(*key))
+    }
+}
+
+impl PartialEq for UserPublicKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl Eq for UserPublicKey {}
+
+impl Hash for UserPublicKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_bytes().hash(state)
+    }
+}
+
+impl From<[u8; 32]> for UserPublicKey {
+    #[inline]
+    fn from(bytes: [u8; 32]) -> UserPublicKey {
+        UserPublicKey(bytes.into())
+    }
+}
+
+impl fmt::Display for UserPublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&bech32::encode(PUBLIC_KEY_HRP, self.as_bytes()))
+    }
+}
+
+impl fmt::Debug for UserPublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "UserPublicKey({})", self)
+    }
+}
+
+impl FromStr for UserPublicKey {
+    type Err = Error;
+
+    /// Also accepts SSH ed25519 public keys, see `UserPublicKey::from_ssh`.
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        if key.starts_with("ssh-") {
+            return UserPublicKey::from_ssh(key);
+        }
+        parse_key32(key, PUBLIC_KEY_HRP).map(|key| UserPublicKey::from(*key))
+    }
+}
+
+impl Recipient for UserPublicKey {
+    fn fingerprint(&self) -> Option<Fingerprint> {
+        Some(UserPublicKey::fingerprint(self))
+    }
+
+    /// With `Encryptor::authenticate_as` the sender's key takes part in the key agreement too.
+    fn wrap_file_key(&self, file_key: &Secret, ctx: &WrapContext) -> Result<Stanza, Error> {
+        let (kind, shared) = match ctx.sender {
+            Some(sender) => (header::STANZA_X25519_AUTHENTICATED, ctx.ephemeral.derive_authenticated_secret(sender, &self.0, 32)),
+            None => (header::STANZA_X25519, ctx.ephemeral.derive_secret(&self.0, 32)),
+        };
+        wrap_with_shared(kind, file_key, &shared, ctx.hints, ctx.scheme)
+    }
+}
+
+impl Identity for UserSecretKey {
+    /// With `Decryptor::expect_sender` only the stanzas authenticated by that sender are tried.
+    fn unwrap_file_key(&self, ctx: &UnwrapContext) -> Result<Option<Secret>, Error> {
+        let (kind, shared) = match ctx.expected_sender {
+            Some(sender) => (header::STANZA_X25519_AUTHENTICATED, self.derive_authenticated_secret(ctx.ephemeral(), &sender.0, 32)),
+            None => (header::STANZA_X25519, self.derive_secret(ctx.ephemeral(), 32)),
+        };
+        let scheme = ctx.scheme();
+        ctx.unwrap_stanzas(kind, &recipient_hint(&shared), |body| decrypt_file_key(&shared, body, scheme))
     }
 }
 
 impl fmt::Display for UserSecretKey {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut private = self.0.to_bytes().to_hex::<String>();
-        let public = self.get_public().as_bytes().to_hex::<String>();
-        writeln!(f, "privateKey: 0x{}", private)?;
-        private.zeroize();
-        writeln!(f, "publicKey: 0x{}", public)
+        writeln!(f, "privateKey: {}", *bech32::encode(SECRET_KEY_HRP, &*self.to_bytes()))?;
+        writeln!(f, "publicKey: {}", self.get_public())?;
+        writeln!(f, "fingerprint: {}", self.get_public().fingerprint())
+    }
+}
+
+/// Parses a key from its bech32 encoding, which must have the `hrp` prefix.
+/// A key in the older hex form doesn't say what kind of key it is, so it's refused with `Error::UntypedHexKey`,
+/// the `from_hex` of the expected kind of key converts it.
+pub fn parse_key32(key: &str, hrp: &'static str) -> Result<Zeroizing<[u8; 32]>, Error> {
+    let hex = key.strip_prefix("0x").unwrap_or(key);
+    if !hex.is_empty() && hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(Error::UntypedHexKey);
     }
+    bech32::decode_key(key, hrp)
 }
 
 pub fn secret_parse_hex32(hex: &str) -> Result<Zeroizing<[u8; 32]>, Error> {
@@ -102,9 +274,9 @@ impl fmt::Debug for UserSecretKey {
 impl FromStr for UserSecretKey {
     type Err = Error;
 
-    fn from_str(hex: &str) -> Result<Self, Self::Err> {
-        let hex = secret_parse_hex32(hex)?;
-        let result = StaticSecret::from(*hex);
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        let key = parse_key32(key, SECRET_KEY_HRP)?;
+        let result = StaticSecret::from(*key);
 
         Ok(UserSecretKey(result))
     }
@@ -147,3 +319,40 @@ impl Drop for UserSecretKey {
         self.zeroize()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustc_hex::ToHex;
+
+    #[test]
+    fn derive_subkeys() {
+        let master = UserSecretKey::from_bytes(&[1u8; 32]);
+        let key = master.derive("project/payments/2026").unwrap();
+        assert_eq!(key.get_public(), master.derive("project").unwrap().derive("payments/2026").unwrap().get_public());
+        // The derivation must never change, or the subkeys of a backed up master key would be lost.
+        assert_eq!(key.get_public().to_string(), "lorenz1ak9dgvgnvex4pga0mvx3jdujqg5sa99nfrr6u322g83f5fpcxssqs3uy2j");
+        assert_ne!(key.get_public(), master.derive("project/payments/2025").unwrap().get_public());
+        assert_ne!(key.get_public(), master.get_public());
+        assert_ne!(master.derive("ab").unwrap().get_public(), master.derive("a/b").unwrap().get_public());
+        for bad in &["", "/project", "project/", "project//payments"] {
+            assert!(matches!(master.derive(bad), Err(Error::BadDerivationLabel(_))));
+        }
+    }
+
+    #[test]
+    fn hex_keys_need_an_explicit_kind() {
+        let key = UserSecretKey::from_bytes(&[3u8; 32]);
+        let hex = format!("0x{}", key.to_bytes().to_hex::<String>());
+        // The same hex could be a private key or a recipient, it's only accepted by the `from_hex` of the expected kind.
+        assert!(matches!(hex.parse::<UserSecretKey>(), Err(Error::UntypedHexKey)));
+        assert!(matches!(hex.parse::<UserPublicKey>(), Err(Error::UntypedHexKey)));
+        assert!(matches!(hex[2..].parse::<UserPublicKey>(), Err(Error::UntypedHexKey)));
+        assert_eq!(UserSecretKey::from_hex(&hex).unwrap().get_public(), key.get_public());
+        assert_eq!(UserSecretKey::from_hex(&hex[2..]).unwrap().get_public(), key.get_public());
+        let public = UserPublicKey::from_hex(&format!("0x{}", key.get_public().as_bytes().to_hex::<String>())).unwrap();
+        assert_eq!(public, key.get_public());
+        assert!(matches!(UserPublicKey::from_hex("0x0102"), Err(Error::BadKeyLength)));
+        assert_eq!(key.get_public().to_string().parse::<UserPublicKey>().unwrap(), key.get_public());
+    }
+}